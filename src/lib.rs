@@ -1,12 +1,14 @@
 use std::borrow::Cow;
 use std::collections::hash_map::Entry;
 use std::collections::{HashMap, VecDeque};
-use std::ffi::OsStr;
+use std::convert::TryInto;
+use std::ffi::{OsStr, OsString};
 use std::io::Write;
 use std::mem;
 use std::path::{Path, PathBuf};
 use std::pin::Pin;
 use std::process::Stdio;
+use std::str::FromStr;
 use std::task::{Context, Poll};
 use std::time::Instant;
 
@@ -20,7 +22,7 @@ use rand::seq::SliceRandom;
 use rand::thread_rng;
 use serde::{Deserialize, Serialize};
 use slog::{debug, error, info, o, trace, warn, Logger};
-use tempfile::NamedTempFile;
+use tempfile::{NamedTempFile, TempDir};
 use thiserror::Error;
 use time::{Duration, OffsetDateTime};
 use tokio::io::{self, AsyncBufReadExt, BufReader};
@@ -38,18 +40,37 @@ pub const BATCH_SIZE: usize = 1000;
 const STDERR_FILE: &str = "stderr.txt";
 /// Name of the file where the test list is saved.
 const TEST_LIST_FILE: &str = "reproduce-list.txt";
+/// Prefix of the comment in a reproduce list that contains the arguments after the test list.
+const EXTRA_ARGS_PREFIX: &str = "# Arguments after the test list:";
+/// Name of the directory where the working directory of a failed process is kept.
+const WORK_DIR: &str = "work-dir";
 /// Directory name where failure folders are stored.
 pub const LOG_FILE: &str = "log.json";
 /// CSV summary file with one test per line.
 pub const CSV_SUMMARY: &str = "summary.csv";
 /// XML junit summary file.
 pub const XML_SUMMARY: &str = "summary.xml";
+/// JSON file with the duration and throughput of the run.
+pub const STATS_SUMMARY: &str = "stats.json";
 /// Directory name where failure folders are stored.
 pub const FAIL_DIR: &str = "fails";
 /// Dummy name if a failure cannot be attributed to a test.
 pub const UNKNOWN_TEST_NAME: &str = "unknown";
 /// These many lines from stderr will be saved in the junit xml result file.
 const LAST_STDERR_LINES: usize = 5;
+/// Report the size of buffered stdout every time it grows by this many bytes.
+pub const BUFFERED_REPORT_SIZE: usize = 64 * 1024;
+/// Stop buffering stderr once it grows past this many bytes.
+pub const MAX_STDERR_SIZE: usize = 1024 * 1024;
+/// These many lines before and after the first fatal error are kept even if stderr is full.
+const FATAL_ERROR_CONTEXT_LINES: usize = 5;
+/// Messages on stderr that indicate that deqp ran out of host or device memory.
+const OOM_SIGNATURES: &[&str] = &[
+    "VK_ERROR_OUT_OF_DEVICE_MEMORY",
+    "VK_ERROR_OUT_OF_HOST_MEMORY",
+    "std::bad_alloc",
+    "Out of memory",
+];
 /// How often to print progress messages when no progress bar is displayed.
 const UPDATE_PROGRESS_INTERVAL: std::time::Duration = std::time::Duration::from_secs(10);
 
@@ -93,9 +114,26 @@ pub struct Options {
     /// Do not retry failing tests to find flakes.
     #[cfg_attr(feature = "bin", clap(long))]
     pub no_retry: bool,
+    /// Only retry tests with this result, e.g. `Crash`. Can be given multiple times.
+    ///
+    /// By default, all failing tests are retried.
+    #[cfg_attr(feature = "bin", clap(long))]
+    pub retry_only: Vec<TestResultType>,
     /// Hide progress bar.
     #[cfg_attr(feature = "bin", clap(short = 'p', long))]
     pub no_progress: bool,
+    /// Run every deqp process in its own temporary directory inside this directory.
+    ///
+    /// deqp loads data files relative to its working directory, so this usually needs
+    /// `--deqp-archive-dir` in the deqp command. Directories of processes with failures are
+    /// kept, in the failure directory if possible.
+    #[cfg_attr(feature = "bin", clap(long))]
+    pub work_dir: Option<PathBuf>,
+    /// Print the tests that would be run and exit without running them.
+    ///
+    /// Tests are still sorted with deqp unless `--no-sort` is given.
+    #[cfg_attr(feature = "bin", clap(long))]
+    pub list_only: bool,
     /// Do not sort before running.
     ///
     /// Sorting also expands wildcards.
@@ -126,6 +164,50 @@ pub struct Options {
     /// This is not necessarily counted accurately.
     #[cfg_attr(feature = "bin", clap(long, default_value = "100"))]
     pub max_failures: usize,
+    /// Count crashes after out-of-memory messages on stderr as `ResourceError`.
+    #[cfg_attr(feature = "bin", clap(long))]
+    pub detect_oom: bool,
+    /// Run deqp with a clean environment.
+    ///
+    /// Only variables that are passed with `--inherit-env` are kept.
+    #[cfg_attr(feature = "bin", clap(long))]
+    pub clean_env: bool,
+    /// Environment variable that is passed to deqp, implies `--clean-env`.
+    ///
+    /// A trailing `*` matches any suffix, e.g. `MESA_*`. Can be given multiple times.
+    #[cfg_attr(feature = "bin", clap(long))]
+    pub inherit_env: Vec<String>,
+    /// Index of the Vulkan device that deqp should use.
+    ///
+    /// Passed to deqp as `--deqp-vk-device-id`.
+    #[cfg_attr(feature = "bin", clap(long))]
+    pub device_id: Option<u32>,
+    /// Vulkan ICD json file of the driver that should be tested.
+    ///
+    /// Passed to deqp in `VK_ICD_FILENAMES`.
+    #[cfg_attr(feature = "bin", clap(long))]
+    pub icd: Option<PathBuf>,
+    /// Vulkan layer that should be enabled, can be given multiple times.
+    ///
+    /// Passed to deqp in `VK_INSTANCE_LAYERS`.
+    #[cfg_attr(feature = "bin", clap(long))]
+    pub layer: Vec<String>,
+    /// Indentation that deqp prints before test results, defaults to two spaces.
+    ///
+    /// An empty prefix accepts any leading whitespace.
+    #[cfg_attr(feature = "bin", clap(long))]
+    pub result_prefix: Option<String>,
+    /// Line that deqp prints when all tests finished, defaults to `DONE!`.
+    ///
+    /// Can be given multiple times.
+    #[cfg_attr(feature = "bin", clap(long))]
+    pub done_marker: Vec<String>,
+    /// Parse numeric values like `fps: 59.8` in `Pass (fps: 59.8)` and save them in the log.
+    #[cfg_attr(feature = "bin", clap(long))]
+    pub parse_metrics: bool,
+    /// Treat output on stderr as progress, so it resets the timeout.
+    #[cfg_attr(feature = "bin", clap(long))]
+    pub stderr_progress: bool,
     /// The deqp command to run. E.g. `./deqp-vk --deqp-caselist-file`
     ///
     /// A filename with the tests cases that should be run is appended to the command.
@@ -160,6 +242,14 @@ pub enum TestResultType {
 pub struct TestResult {
     pub stdout: String,
     pub variant: TestResultType,
+    /// Reason that deqp reported for a test that is not supported or passed with a warning.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+    /// Numeric values that deqp reported with the result, e.g. `fps` for `Pass (fps: 59.8)`.
+    ///
+    /// Only filled if [`RunDeqpConfig::parse_metrics`] is set.
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub metrics: HashMap<String, f64>,
 }
 
 #[derive(Clone, Debug, Deserialize, Serialize)]
@@ -179,11 +269,36 @@ pub struct TestResultData<'a> {
 #[derive(Debug)]
 pub struct ReproducibleTestResultData<'a, 'list> {
     pub data: TestResultData<'a>,
+    /// Position of the test in the list that was passed to [`run_test_list`].
+    pub index: usize,
     /// The last test in this list is the one the test result is about.
     pub run_list: &'list [&'a str],
     pub args: &'a [String],
 }
 
+/// Short version of a test result without the output of the test, e.g. to update a table.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct TestSummary<'a> {
+    /// Position of the test in the list of the job, does not change when deqp is restarted.
+    pub index: usize,
+    /// Name of the deqp test.
+    pub name: &'a str,
+    pub variant: TestResultType,
+    pub duration: Duration,
+}
+
+impl<'a, 'list> ReproducibleTestResultData<'a, 'list> {
+    /// Get the summary of this result, the full output can be fetched from `self` if needed.
+    pub fn summary(&self) -> TestSummary<'a> {
+        TestSummary {
+            index: self.index,
+            name: self.data.name,
+            variant: self.data.result.variant.clone(),
+            duration: self.data.duration,
+        }
+    }
+}
+
 #[derive(Debug, Error)]
 pub enum DeqpSortError {
     #[error("Failed to create temporary file : {0}")]
@@ -232,11 +347,37 @@ pub enum DeqpError {
     #[error("Failed to start deqp: {0}")]
     StartError(String),
     /// Fatal error
-    #[error("deqp did not run any tests")]
-    NoTestsRun,
+    #[error("{}", format_no_tests_run(.exit_status, .end, .stderr))]
+    NoTestsRun {
+        /// The exit code of deqp, `None` if it did not exit by itself.
+        exit_status: Option<i32>,
+        /// How the deqp process ended.
+        #[serde(default)]
+        end: ProcessEnd,
+        /// The last lines of stderr, which usually explain the error.
+        stderr: String,
+    },
     /// Fatal error
     #[error("failedto get deqp process exit code")]
     NoProcessResult,
+    #[error("deqp was cancelled")]
+    Cancelled,
+}
+
+/// How a deqp process ended, see [`DeqpError::NoTestsRun`].
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+pub enum ProcessEnd {
+    /// The process exited with an exit code.
+    Exited,
+    /// The process was killed by a signal that was not sent by deqp-runner.
+    Signal,
+    /// The process was killed because it timed out.
+    Timeout,
+    /// The process was killed because the run was cancelled.
+    Cancelled,
+    /// The exit status of the process is not known.
+    #[default]
+    Unknown,
 }
 
 #[derive(Debug, Deserialize, Serialize)]
@@ -247,8 +388,18 @@ pub struct DeqpErrorWithOutput {
 
 #[derive(Debug)]
 pub enum DeqpEvent {
-    TestStart { name: String },
-    TestEnd { result: TestResult },
+    TestStart {
+        name: String,
+    },
+    TestEnd {
+        result: TestResult,
+    },
+    /// The output of the current test grew by another [`BUFFERED_REPORT_SIZE`] bytes.
+    ///
+    /// Allows to detect tests that produce a lot of output before they finish.
+    OutputBuffered {
+        stdout_len: usize,
+    },
 }
 
 #[derive(Clone, Debug)]
@@ -260,7 +411,49 @@ pub struct RunOptions {
     /// Directory where failure dumps should be created.
     pub fail_dir: Option<PathBuf>,
     pub retry: bool,
+    /// If not empty, only failing tests with one of these results are retried.
+    pub retry_results: Vec<TestResultType>,
     pub batch_size: usize,
+    /// Count a crashing test as [`TestResultType::ResourceError`] if deqp printed an
+    /// out-of-memory message on stderr while running it.
+    pub detect_oom: bool,
+    /// If set, deqp is started with a clean environment that contains only these variables.
+    ///
+    /// A trailing `*` matches any suffix.
+    pub inherit_env: Option<Vec<String>>,
+    /// Additional environment variables for deqp, e.g. `VK_ICD_FILENAMES` to select a driver.
+    pub env: Vec<(String, String)>,
+    /// Arguments that are appended after the test list file, e.g. `--deqp-vk-device-id=1`.
+    pub extra_args: Vec<String>,
+    /// Every deqp process runs in a new temporary directory inside this directory.
+    ///
+    /// This keeps files that deqp writes into its working directory apart. The directory is
+    /// removed afterwards, unless the process had a failure. Then it is moved to `work-dir` in
+    /// the failure directory if possible, otherwise it stays where it is.
+    pub work_dir: Option<PathBuf>,
+    /// Prefix of result lines like `  Pass (OK)`, any leading whitespace if `None`.
+    ///
+    /// See [`RunDeqpConfig::result_prefix`].
+    pub result_prefix: Option<String>,
+    /// Lines that deqp prints when all tests finished, see [`RunDeqpConfig::done_markers`].
+    pub done_markers: Vec<String>,
+    /// Parse numeric metrics from result lines, see [`RunDeqpConfig::parse_metrics`].
+    pub parse_metrics: bool,
+    /// Reset the timeout on stderr output, see [`RunDeqpConfig::stderr_progress`].
+    pub stderr_progress: bool,
+    /// Stop starting new jobs once this is set to `true`.
+    ///
+    /// Jobs that are already running are finished and their results are recorded.
+    pub shutdown: Option<tokio::sync::watch::Receiver<bool>>,
+}
+
+impl RunOptions {
+    /// If a test with this result in the first run should be run again to find flakes.
+    pub fn should_retry(&self, result: &TestResultType) -> bool {
+        self.retry
+            && result.is_failure()
+            && (self.retry_results.is_empty() || self.retry_results.contains(result))
+    }
 }
 
 #[derive(Debug)]
@@ -315,17 +508,76 @@ enum Job<'a> {
     },
 }
 
+/// Options for starting deqp with [`run_deqp_with_config`].
+#[derive(Clone, Debug)]
+pub struct RunDeqpConfig {
+    /// Kill deqp if no test finished for this long.
+    ///
+    /// Defaults to 15 minutes, like the `--timeout` option.
+    pub timeout: std::time::Duration,
+    /// Additional environment variables for deqp.
+    pub env: Vec<(String, String)>,
+    /// If set, deqp is started with a clean environment and only variables that match one of
+    /// these patterns are passed on. A trailing `*` matches any suffix.
+    ///
+    /// `env` is set in any case.
+    pub inherit_env: Option<Vec<String>>,
+    /// Cancel the run once this is set to `true`.
+    ///
+    /// deqp gets killed and the stream ends with [`DeqpError::Cancelled`] as `finished_result`.
+    pub cancel: Option<tokio::sync::watch::Receiver<bool>>,
+    /// The indentation that deqp uses before test results like `  Pass (OK)`.
+    ///
+    /// Defaults to two spaces. With `None`, any non-empty run of leading whitespace is accepted.
+    pub result_prefix: Option<String>,
+    /// The lines that deqp prints when all tests finished.
+    ///
+    /// Defaults to `DONE!`. Output after such a line is not parsed for test results anymore.
+    pub done_markers: Vec<String>,
+    /// Treat output on stderr as progress, which resets the timeout.
+    ///
+    /// Off by default, so a test that only loops on printing errors is still detected as hanging.
+    pub stderr_progress: bool,
+    /// Parse `key: value` pairs with numeric values from result lines into
+    /// [`TestResult::metrics`].
+    pub parse_metrics: bool,
+    /// Working directory of deqp, defaults to the current directory.
+    ///
+    /// A relative path to the deqp executable is still resolved against the current directory.
+    pub work_dir: Option<PathBuf>,
+}
+
 pub struct RunDeqpState {
     logger: Logger,
     pub pid: u32,
+    /// Command line of the started process, including the program.
+    pub args: Vec<String>,
     timeout_duration: std::time::Duration,
     timeout: Pin<Box<Sleep>>,
     stdout_reader: Pin<Box<io::Lines<BufReader<ChildStdout>>>>,
     stderr_reader: Pin<Box<io::Lines<BufReader<ChildStderr>>>>,
     /// Buffer for stdout
     pub stdout: String,
-    /// Buffer for stderr
+    /// Size of `stdout` in multiples of [`BUFFERED_REPORT_SIZE`] that was last reported.
+    stdout_reported: usize,
+    /// Buffer for stderr, limited to roughly [`MAX_STDERR_SIZE`] bytes
     pub stderr: String,
+    /// Last line that was written to `stderr`, used to collapse repeated lines.
+    stderr_last_line: Option<String>,
+    /// `stderr_last_line` was written although `stderr` was full.
+    stderr_last_forced: bool,
+    /// Number of times `stderr_last_line` was repeated and not yet written to `stderr`.
+    stderr_repeated: usize,
+    /// Number of stderr lines that were dropped because `stderr` was full.
+    stderr_skipped: usize,
+    /// The last dropped lines, written before the first fatal error.
+    stderr_dropped: VecDeque<String>,
+    /// Lines after the first fatal error that are kept even if `stderr` is full.
+    fatal_error_context: usize,
+    /// Reset the timeout for every line on stderr.
+    stderr_progress: bool,
+    /// Parse metrics from result lines.
+    parse_metrics: bool,
     stdout_finished: bool,
     stderr_finished: bool,
     /// Process exited
@@ -334,23 +586,43 @@ pub struct RunDeqpState {
     tests_done: bool,
     /// deqp reported a fatal error on stderr
     has_fatal_error: bool,
+    /// deqp reported an out-of-memory error on stderr since the current test started
+    has_oom: bool,
     /// Process exit status
     pub finished_result: Option<Result<(), DeqpError>>,
+    /// Exit status of the process, `None` if it was killed because of a timeout or cancellation.
+    pub exit_status: Option<std::process::ExitStatus>,
+    /// Number of tests that reported a result.
+    ///
+    /// Tests that were running when deqp crashed or timed out are not counted.
+    pub tests_completed: u64,
     child: Option<tokio::process::Child>,
+    /// Resolves when the run should be cancelled.
+    cancel: Option<Pin<Box<dyn Future<Output = ()> + Send>>>,
+    /// Prefix of result lines, any leading whitespace if `None`.
+    result_prefix: Option<String>,
+    /// Lines that deqp prints after all tests are run.
+    done_markers: Vec<String>,
 }
 
 struct RunTestListState<'a, 'list> {
     logger: Logger,
     /// Input to the process that was last started.
     tests: &'list [&'a str],
+    /// Position of the first test of `tests` in the list that was passed to [`run_test_list`].
+    tests_offset: usize,
     options: &'a RunOptions,
     running: Option<RunDeqpState>,
     /// Index into current `tests` and start time.
-    cur_test: Option<(usize, OffsetDateTime)>,
+    ///
+    /// The wall clock time is reported, the monotonic time is used to compute the duration.
+    cur_test: Option<(usize, OffsetDateTime, Instant)>,
     last_finished: Option<usize>,
 
     /// Temporary file that contains the test list and is passed to deqp.
     test_list_file: Option<NamedTempFile>,
+    /// Working directory of the current process, if [`RunOptions::work_dir`] is set.
+    work_dir: Option<TempDir>,
     /// If the current run had a failure and we already created a failure dir, this is the
     /// directory.
     fail_dir: Option<String>,
@@ -365,7 +637,7 @@ mod serde_io_error {
 
     pub fn deserialize<'de, D: Deserializer<'de>>(de: D) -> Result<std::io::Error, D::Error> {
         let s: String = Deserialize::deserialize(de)?;
-        Ok(std::io::Error::new(std::io::ErrorKind::Other, s))
+        Ok(std::io::Error::other(s))
     }
 }
 
@@ -373,11 +645,44 @@ impl DeqpError {
     pub fn is_fatal(&self) -> bool {
         matches!(
             self,
-            Self::SpawnFailed(_) | Self::StartError(_) | Self::NoTestsRun | Self::NoProcessResult
+            Self::SpawnFailed(_)
+                | Self::StartError(_)
+                | Self::NoTestsRun { .. }
+                | Self::NoProcessResult
         )
     }
 }
 
+fn format_no_tests_run(exit_status: &Option<i32>, end: &ProcessEnd, stderr: &str) -> String {
+    let status = match (end, exit_status) {
+        (ProcessEnd::Exited, Some(code)) => format!("exit status {code}"),
+        (ProcessEnd::Signal, _) => "killed by signal".to_string(),
+        (ProcessEnd::Timeout, _) => "timed out".to_string(),
+        (ProcessEnd::Cancelled, _) => "cancelled".to_string(),
+        (ProcessEnd::Exited, None) | (ProcessEnd::Unknown, _) => "unknown exit status".to_string(),
+    };
+    let mut res = format!("deqp did not run any tests ({status})");
+    if !stderr.is_empty() {
+        res.push_str(", stderr:\n");
+        res.push_str(stderr);
+    }
+    res
+}
+
+impl FromStr for TestResultType {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s == "Missing" {
+            return Ok(Self::Missing);
+        }
+        RESULT_VARIANTS
+            .get(s)
+            .cloned()
+            .ok_or_else(|| format!("Unknown test result '{s}'"))
+    }
+}
+
 impl TestResultType {
     /// If the test result is a failure and the test should be retested.
     pub fn is_failure(&self) -> bool {
@@ -418,17 +723,31 @@ impl<'a> From<DeqpErrorWithOutput> for JobEvent<'a> {
     }
 }
 
+impl Default for RunDeqpConfig {
+    fn default() -> Self {
+        Self {
+            timeout: std::time::Duration::from_secs(900),
+            env: Vec::new(),
+            inherit_env: None,
+            cancel: None,
+            result_prefix: Some("  ".into()),
+            done_markers: vec!["DONE!".into()],
+            stderr_progress: false,
+            parse_metrics: false,
+            work_dir: None,
+        }
+    }
+}
+
 impl RunDeqpState {
     fn new(
         mut logger: Logger,
-        timeout_duration: std::time::Duration,
+        config: &RunDeqpConfig,
+        args: Vec<String>,
         mut child: Child,
     ) -> Result<Self, DeqpError> {
         let pid = child.id().ok_or_else(|| {
-            DeqpError::SpawnFailed(std::io::Error::new(
-                std::io::ErrorKind::Other,
-                "Failed to get child pid",
-            ))
+            DeqpError::SpawnFailed(std::io::Error::other("Failed to get child pid"))
         })?;
         logger = logger.new(o!("pid" => pid));
 
@@ -437,23 +756,54 @@ impl RunDeqpState {
         Ok(Self {
             logger,
             pid,
-            timeout_duration,
-            timeout: Box::pin(tokio::time::sleep(timeout_duration)),
+            args,
+            timeout_duration: config.timeout,
+            timeout: Box::pin(tokio::time::sleep(config.timeout)),
             stdout_reader: Box::pin(BufReader::new(stdout).lines()),
             stderr_reader: Box::pin(BufReader::new(stderr).lines()),
             stdout: String::new(),
+            stdout_reported: 0,
             stderr: String::new(),
+            stderr_last_line: None,
+            stderr_last_forced: false,
+            stderr_repeated: 0,
+            stderr_skipped: 0,
+            stderr_dropped: VecDeque::new(),
+            fatal_error_context: 0,
+            stderr_progress: config.stderr_progress,
+            parse_metrics: config.parse_metrics,
             stdout_finished: false,
             stderr_finished: false,
             finished: false,
             has_timeout: false,
             tests_done: false,
             has_fatal_error: false,
+            has_oom: false,
             finished_result: None,
+            exit_status: None,
+            tests_completed: 0,
             child: Some(child),
+            cancel: config
+                .cancel
+                .clone()
+                .map(|c| Box::pin(wait_for_cancel(c)) as Pin<Box<dyn Future<Output = ()> + Send>>),
+            result_prefix: config.result_prefix.clone(),
+            done_markers: config.done_markers.clone(),
         })
     }
 
+    /// Kill deqp without waiting for it to exit.
+    ///
+    /// The signal is sent immediately and does not depend on a spawned task. The child stays in
+    /// the state, so it is reaped when the state is polled further or dropped.
+    fn kill(&mut self) {
+        if let Some(child) = &mut self.child {
+            if let Err(e) = child.start_kill() {
+                error!(self.logger, "Failed to kill deqp"; "error" => %e);
+            }
+        }
+    }
+
     fn handle_stdout_line(
         &mut self,
         l: Result<Option<String>, std::io::Error>,
@@ -475,10 +825,21 @@ impl RunDeqpState {
                 self.stdout.push_str(&l);
                 self.stdout.push('\n');
             }
-            return None;
+            return self.report_buffered();
         }
 
-        if let Some(l) = l.strip_prefix("  ") {
+        let result_line = match &self.result_prefix {
+            Some(prefix) => l.strip_prefix(prefix.as_str()),
+            None => {
+                let trimmed = l.trim_start();
+                if trimmed.len() < l.len() {
+                    Some(trimmed)
+                } else {
+                    None
+                }
+            }
+        };
+        if let Some(l) = result_line {
             for (s, res) in &*RESULT_VARIANTS {
                 if let Some(l) = l.strip_prefix(s) {
                     let mut l = l.trim();
@@ -486,11 +847,30 @@ impl RunDeqpState {
                         l = &l[1..l.len() - 1];
                     }
                     self.stdout.push_str(l);
+                    let reason = if matches!(
+                        res,
+                        TestResultType::NotSupported
+                            | TestResultType::QualityWarning
+                            | TestResultType::CompatibilityWarning
+                    ) {
+                        Some(l.to_string())
+                    } else {
+                        None
+                    };
+                    let metrics = if self.parse_metrics {
+                        parse_metrics(l)
+                    } else {
+                        HashMap::new()
+                    };
                     self.timeout = Box::pin(tokio::time::sleep(self.timeout_duration));
+                    self.tests_completed += 1;
+                    self.stdout_reported = 0;
                     return Some(DeqpEvent::TestEnd {
                         result: TestResult {
                             stdout: mem::take(&mut self.stdout),
                             variant: res.clone(),
+                            reason,
+                            metrics,
                         },
                     });
                 }
@@ -498,25 +878,41 @@ impl RunDeqpState {
         }
 
         if let Some(l) = l.strip_prefix("TEST: ") {
+            self.has_oom = false;
             return Some(DeqpEvent::TestStart {
                 name: l.to_string(),
             });
         } else if let Some(l) = l.strip_prefix("Test case '") {
             if let Some(l) = l.strip_suffix("'..") {
                 self.stdout.clear();
+                self.stdout_reported = 0;
+                self.has_oom = false;
                 return Some(DeqpEvent::TestStart { name: l.into() });
             } else {
                 self.stdout.push_str(l);
                 self.stdout.push('\n');
             }
-        } else if l == "DONE!" {
+        } else if self.done_markers.contains(&l) {
             self.tests_done = true;
         } else if l.is_empty() {
         } else {
             self.stdout.push_str(&l);
             self.stdout.push('\n');
         }
-        None
+        self.report_buffered()
+    }
+
+    /// Report the size of `stdout` if it grew past the next multiple of [`BUFFERED_REPORT_SIZE`].
+    fn report_buffered(&mut self) -> Option<DeqpEvent> {
+        let reported = self.stdout.len() / BUFFERED_REPORT_SIZE;
+        if reported > self.stdout_reported {
+            self.stdout_reported = reported;
+            Some(DeqpEvent::OutputBuffered {
+                stdout_len: self.stdout.len(),
+            })
+        } else {
+            None
+        }
     }
 
     fn handle_stderr_line(&mut self, l: Result<Option<String>, std::io::Error>) {
@@ -532,12 +928,91 @@ impl RunDeqpState {
                 return;
             }
         };
-        if l.contains("FATAL ERROR: ") {
+        if self.stderr_progress {
+            self.timeout = Box::pin(tokio::time::sleep(self.timeout_duration));
+        }
+        let mut keep = false;
+        let mut first_fatal = false;
+        if l.contains("FATAL ERROR: ") && !self.has_fatal_error {
             warn!(self.logger, "Deqp encountered fatal error"; "error" => &l);
             self.has_fatal_error = true;
+            keep = true;
+            first_fatal = true;
+            self.fatal_error_context = FATAL_ERROR_CONTEXT_LINES;
+        }
+        if OOM_SIGNATURES.iter().any(|s| l.contains(s)) {
+            debug!(self.logger, "Deqp ran out of memory"; "error" => &l);
+            self.has_oom = true;
+        }
+
+        if self.stderr_last_line.as_ref() == Some(&l) {
+            self.stderr_repeated += 1;
+            return;
+        }
+        self.flush_stderr_repeated();
+
+        if first_fatal {
+            // Write the lines before the fatal error that were dropped
+            self.stderr_skipped -= self.stderr_dropped.len();
+            self.write_stderr_skipped();
+            for d in mem::take(&mut self.stderr_dropped) {
+                self.stderr.push_str(&d);
+                self.stderr.push('\n');
+            }
+        } else if !keep && self.fatal_error_context > 0 {
+            self.fatal_error_context -= 1;
+            keep = true;
+        }
+        let fits = self.stderr.len() + l.len() < MAX_STDERR_SIZE;
+        if keep || fits {
+            self.stderr.push_str(&l);
+            self.stderr.push('\n');
+            self.stderr_last_line = Some(l);
+            self.stderr_last_forced = !fits;
+        } else {
+            self.stderr_skipped += 1;
+            self.stderr_last_line = None;
+            if !self.has_fatal_error {
+                if self.stderr_dropped.len() >= FATAL_ERROR_CONTEXT_LINES {
+                    self.stderr_dropped.pop_front();
+                }
+                self.stderr_dropped.push_back(l);
+            }
+        }
+    }
+
+    /// Write the number of repetitions of the last stderr line to `stderr`.
+    ///
+    /// If `stderr` is full, the repetitions are counted as skipped lines, unless the repeated line
+    /// itself was kept although `stderr` was full.
+    fn flush_stderr_repeated(&mut self) {
+        if self.stderr_repeated > 0 {
+            let note = format!("[previous line repeated {} times]\n", self.stderr_repeated);
+            if self.stderr_last_forced || self.stderr.len() + note.len() < MAX_STDERR_SIZE {
+                self.stderr.push_str(&note);
+            } else {
+                self.stderr_skipped += self.stderr_repeated;
+            }
+            self.stderr_repeated = 0;
+        }
+    }
+
+    /// Write the number of skipped stderr lines to `stderr`.
+    fn write_stderr_skipped(&mut self) {
+        if self.stderr_skipped > 0 {
+            self.stderr.push_str(&format!(
+                "[{} lines of stderr skipped]\n",
+                self.stderr_skipped
+            ));
+            self.stderr_skipped = 0;
         }
-        self.stderr.push_str(&l);
-        self.stderr.push('\n');
+    }
+
+    /// Write pending notes about collapsed and skipped lines to `stderr`.
+    fn finish_stderr(&mut self) {
+        self.flush_stderr_repeated();
+        self.write_stderr_skipped();
+        self.stderr_dropped.clear();
     }
 }
 
@@ -546,12 +1021,14 @@ impl<'a, 'list> RunTestListState<'a, 'list> {
         RunTestListState {
             logger,
             tests,
+            tests_offset: 0,
             options,
             running: None,
             cur_test: None,
             last_finished: None,
 
             test_list_file: None,
+            work_dir: None,
             fail_dir: None,
         }
     }
@@ -569,14 +1046,12 @@ impl<'a, 'list> RunTestListState<'a, 'list> {
             )),
             stdout: String::new(),
         })?;
-        for t in self.tests {
-            writeln!(&mut temp_file, "{t}").map_err(|e| DeqpErrorWithOutput {
-                error: DeqpError::StartError(format!(
-                    "Failed to write temporary file for test list: {e}"
-                )),
-                stdout: String::new(),
-            })?;
-        }
+        write_test_list(&mut temp_file, self.tests).map_err(|e| DeqpErrorWithOutput {
+            error: DeqpError::StartError(format!(
+                "Failed to write temporary file for test list: {e}"
+            )),
+            stdout: String::new(),
+        })?;
 
         let mut args = self.options.args.clone();
         args.push(
@@ -593,6 +1068,20 @@ impl<'a, 'list> RunTestListState<'a, 'list> {
                 })?
                 .into(),
         );
+        args.extend(self.options.extra_args.iter().cloned());
+
+        if let Some(dir) = &self.options.work_dir {
+            let work_dir = tempfile::Builder::new()
+                .prefix("deqp-")
+                .tempdir_in(dir)
+                .map_err(|e| DeqpErrorWithOutput {
+                    error: DeqpError::StartError(format!(
+                        "Failed to create working directory in {dir:?}: {e}"
+                    )),
+                    stdout: String::new(),
+                })?;
+            self.work_dir = Some(work_dir);
+        }
         self.cur_test = None;
         self.last_finished = None;
         self.fail_dir = None;
@@ -614,7 +1103,7 @@ impl<'a, 'list> RunTestListState<'a, 'list> {
                         if let Err(e) = std::fs::create_dir_all(&new_dir) {
                             error!(self.logger, "Failed to create failure directory";
                                 "error" => %e);
-                            return;
+                            break;
                         }
                         self.fail_dir = Some(dir_name);
                         // Write reproduce-list.txt
@@ -622,32 +1111,31 @@ impl<'a, 'list> RunTestListState<'a, 'list> {
                             Ok(mut f) => {
                                 if let Err(e) = (|| -> Result<(), std::io::Error> {
                                     // Write options
-                                    write!(&mut f, "#!")?;
-                                    if self
+                                    // Save the values of inherited variables, so the
+                                    // environment is the same when reproducing
+                                    let mut env = self
                                         .options
-                                        .args
-                                        .first()
-                                        .map(|a| !a.starts_with('/'))
+                                        .inherit_env
+                                        .as_deref()
+                                        .map(inherited_env)
                                         .unwrap_or_default()
-                                    {
-                                        write!(&mut f, "/usr/bin/env -S ")?;
+                                        .into_iter()
+                                        .map(|(k, v)| {
+                                            (
+                                                k.to_string_lossy().into_owned(),
+                                                v.to_string_lossy().into_owned(),
+                                            )
+                                        })
+                                        .collect::<Vec<_>>();
+                                    env.extend(self.options.env.iter().cloned());
+                                    RunCommand {
+                                        args: self.options.args.clone(),
+                                        env,
+                                        clean_env: self.options.inherit_env.is_some(),
+                                        extra_args: self.options.extra_args.clone(),
                                     }
-                                    writeln!(
-                                        &mut f,
-                                        "{}",
-                                        self.options
-                                            .args
-                                            .iter()
-                                            .map(|a| a.replace('\n', "\\n"))
-                                            .collect::<Vec<_>>()
-                                            .join(" ")
-                                    )?;
-
-                                    // Write tests
-                                    for t in self.tests {
-                                        writeln!(&mut f, "{t}")?;
-                                    }
-                                    Ok(())
+                                    .write(&mut f)?;
+                                    write_test_list(&mut f, self.tests)
                                 })() {
                                     error!(self.logger, "Failed to write reproduce list";
                                         "error" => %e);
@@ -664,11 +1152,29 @@ impl<'a, 'list> RunTestListState<'a, 'list> {
             }
         }
 
-        if let Some(running) = &mut self.running {
-            if !running.stderr.is_empty() {
-                // Save current stderr
-                let stderr = mem::take(&mut running.stderr);
-                self.save_fail_dir_stderr(&stderr);
+        // Keep the files that the failed process wrote
+        if let Some(work_dir) = self.work_dir.take() {
+            let mut path = work_dir.into_path();
+            if let (Some(dir), Some(fail_dir)) = (&self.options.fail_dir, &self.fail_dir) {
+                // Renaming fails if the directories are on different file systems, then the
+                // directory stays where it is.
+                let new_path = dir.join(fail_dir).join(WORK_DIR);
+                if std::fs::rename(&path, &new_path).is_ok() {
+                    path = new_path;
+                }
+            }
+            info!(self.logger, "Keeping working directory of failed process";
+                "path" => ?path);
+        }
+
+        // Only move stderr out when it can be saved, otherwise it is lost
+        if self.fail_dir.is_some() {
+            if let Some(running) = &mut self.running {
+                if !running.stderr.is_empty() {
+                    // Save current stderr
+                    let stderr = mem::take(&mut running.stderr);
+                    self.save_fail_dir_stderr(&stderr);
+                }
             }
         }
     }
@@ -706,11 +1212,14 @@ impl<'a, 'list> RunTestListState<'a, 'list> {
         (0..count)
             .map(|i| {
                 RunTestListEvent::TestResult(ReproducibleTestResultData {
+                    index: self.tests_offset + start + i,
                     data: TestResultData {
                         name: self.tests[start + i],
                         result: TestResult {
                             stdout: String::new(),
                             variant: TestResultType::Missing,
+                            reason: None,
+                            metrics: HashMap::new(),
                         },
                         start: OffsetDateTime::now_utc(),
                         duration: Duration::new(0, 0),
@@ -728,7 +1237,7 @@ impl<'a, 'list> RunTestListState<'a, 'list> {
         trace!(self.logger, "Test started"; "test" => name);
         let next_test = self.last_finished.map(|i| i + 1).unwrap_or_default();
         if let Some(i) = self.tests[next_test..].iter().position(|t| t == &name) {
-            self.cur_test = Some((next_test + i, OffsetDateTime::now_utc()));
+            self.cur_test = Some((next_test + i, OffsetDateTime::now_utc(), Instant::now()));
             self.get_missing(i)
         } else {
             warn!(self.logger, "Executing unknown test"; "test" => name);
@@ -740,13 +1249,14 @@ impl<'a, 'list> RunTestListState<'a, 'list> {
         trace!(self.logger, "Test end"; "cur_test" => ?self.cur_test, "result" => ?result);
         if let Some(cur_test) = self.cur_test.take() {
             self.last_finished = Some(cur_test.0);
-            let duration = OffsetDateTime::now_utc() - cur_test.1;
+            let duration = elapsed_since(cur_test.2);
             let is_failure = result.variant.is_failure();
             if is_failure {
                 self.create_fail_dir(self.tests[cur_test.0]);
             }
 
             Some(RunTestListEvent::TestResult(ReproducibleTestResultData {
+                index: self.tests_offset + cur_test.0,
                 data: TestResultData {
                     name: self.tests[cur_test.0],
                     result,
@@ -776,7 +1286,7 @@ impl<'a, 'list> RunTestListState<'a, 'list> {
         let mut is_failure = true;
         let pid = state.pid;
         let res = if let Some(cur_test) = self.cur_test {
-            let duration = OffsetDateTime::now_utc() - cur_test.1;
+            let duration = elapsed_since(cur_test.2);
             self.create_fail_dir(self.tests[cur_test.0]);
 
             if state
@@ -791,18 +1301,25 @@ impl<'a, 'list> RunTestListState<'a, 'list> {
 
             // Continue testing
             let run_list = &self.tests[..cur_test.0 + 1];
+            let index = self.tests_offset + cur_test.0;
             self.tests = &self.tests[cur_test.0 + 1..];
+            self.tests_offset += cur_test.0 + 1;
 
             let result_data = RunTestListEvent::TestResult(ReproducibleTestResultData {
+                index,
                 data: TestResultData {
                     name: run_list[cur_test.0],
                     result: TestResult {
                         stdout: state.stdout,
                         variant: if matches!(state.finished_result, Some(Err(DeqpError::Timeout))) {
                             TestResultType::Timeout
+                        } else if self.options.detect_oom && state.has_oom {
+                            TestResultType::ResourceError
                         } else {
                             TestResultType::Crash
                         },
+                        reason: None,
+                        metrics: HashMap::new(),
                     },
                     start: cur_test.1,
                     duration,
@@ -849,8 +1366,23 @@ impl<'a, 'list> RunTestListState<'a, 'list> {
                 // No test executed, counts as fatal error
                 self.tests = &[];
                 warn!(self.logger, "Deqp exited without running tests, aborting"; "error" => ?e);
+                let (exit_status, end) = match (&e, state.exit_status) {
+                    (DeqpError::Timeout, _) => (None, ProcessEnd::Timeout),
+                    (DeqpError::Cancelled, _) => (None, ProcessEnd::Cancelled),
+                    (_, Some(status)) => match status.code() {
+                        Some(code) => (Some(code), ProcessEnd::Exited),
+                        None => (None, ProcessEnd::Signal),
+                    },
+                    (_, None) => (None, ProcessEnd::Unknown),
+                };
+                let stderr = state.stderr.lines().collect::<Vec<_>>();
+                let stderr = stderr[stderr.len().saturating_sub(LAST_STDERR_LINES)..].join("\n");
                 vec![RunTestListEvent::DeqpError(DeqpErrorWithOutput {
-                    error: DeqpError::NoTestsRun,
+                    error: DeqpError::NoTestsRun {
+                        exit_status,
+                        end,
+                        stderr,
+                    },
                     stdout: state.stdout,
                 })]
             }
@@ -891,10 +1423,10 @@ impl<'a> Job<'a> {
                         trace!(logger2, "First run test result");
                         match r {
                             RunTestListEvent::TestResult(res) => {
-                                let is_failure = res.data.result.variant.is_failure();
+                                let retry = options.should_retry(&res.data.result.variant);
                                 let entry = res.data.into();
                                 // Start second run for failed tests
-                                if is_failure && options.retry {
+                                if retry {
                                     let new_job =
                                         JobEvent::NewJob(Job::SecondRun { list: res.run_list });
                                     Either::Left(stream::iter(vec![entry, new_job]))
@@ -1066,6 +1598,176 @@ pub fn parse_test_file(content: &str) -> Vec<&str> {
         .collect()
 }
 
+/// The deqp command at the start of a reproduce list.
+///
+/// The first line is a shebang, so the list can be executed to run the tests again. Arguments
+/// after the test list do not fit into a shebang, they are saved in a comment.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct RunCommand {
+    /// The deqp executable and the arguments before the test list.
+    pub args: Vec<String>,
+    /// Environment variables that are set for deqp.
+    pub env: Vec<(String, String)>,
+    /// If deqp is started with only the variables in `env`.
+    pub clean_env: bool,
+    /// Arguments after the test list.
+    pub extra_args: Vec<String>,
+}
+
+impl RunCommand {
+    /// Read the command from the start of a test list, `None` if it has no shebang.
+    pub fn parse(content: &str) -> Option<Self> {
+        let mut lines = content.lines();
+        let cmd = lines.next()?.strip_prefix("#!")?.trim();
+        let mut res = Self::default();
+        if let Some(cmd) = cmd.strip_prefix("/usr/bin/env -S ") {
+            let mut words = split_args(cmd).into_iter().peekable();
+            if words.peek().map(|w| w == "-i").unwrap_or_default() {
+                res.clean_env = true;
+                words.next();
+            }
+            while let Some(var) = words.peek().and_then(|w| parse_env_assignment(w)) {
+                res.env.push(var);
+                words.next();
+            }
+            res.args = words.collect();
+        } else {
+            res.args = split_args(cmd);
+        }
+
+        for l in lines.map(str::trim).take_while(|l| l.starts_with('#')) {
+            if let Some(args) = l.strip_prefix(EXTRA_ARGS_PREFIX) {
+                res.extra_args = split_args(args);
+            }
+        }
+        Some(res)
+    }
+
+    /// Write the command as shebang and comment, in the format that [`Self::parse`] reads.
+    pub fn write<W: Write>(&self, mut w: W) -> std::io::Result<()> {
+        write!(w, "#!")?;
+        if !self.env.is_empty()
+            || self.clean_env
+            || self
+                .args
+                .first()
+                .map(|a| !a.starts_with('/'))
+                .unwrap_or_default()
+            || self.args.iter().any(|a| needs_quotes(a))
+        {
+            write!(w, "/usr/bin/env -S ")?;
+            if self.clean_env {
+                write!(w, "-i ")?;
+            }
+            for (k, v) in &self.env {
+                write!(w, "{k}={} ", quote_arg(v))?;
+            }
+        }
+        writeln!(w, "{}", join_args(&self.args))?;
+        if !self.extra_args.is_empty() {
+            writeln!(w, "{EXTRA_ARGS_PREFIX} {}", join_args(&self.extra_args))?;
+        }
+        Ok(())
+    }
+}
+
+fn needs_quotes(arg: &str) -> bool {
+    arg.is_empty()
+        || arg
+            .chars()
+            .any(|c| c.is_whitespace() || matches!(c, '"' | '\'' | '\\' | '$' | '#'))
+}
+
+/// Quote an argument so that `env -S` and [`split_args`] read it as one word.
+fn quote_arg(arg: &str) -> Cow<'_, str> {
+    if !needs_quotes(arg) {
+        return Cow::Borrowed(arg);
+    }
+    let mut res = String::from('"');
+    for c in arg.chars() {
+        match c {
+            '"' | '\\' | '$' => {
+                res.push('\\');
+                res.push(c);
+            }
+            '\n' => res.push_str("\\n"),
+            '\t' => res.push_str("\\t"),
+            _ => res.push(c),
+        }
+    }
+    res.push('"');
+    Cow::Owned(res)
+}
+
+fn join_args(args: &[String]) -> String {
+    args.iter()
+        .map(|a| quote_arg(a))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Split a command line at whitespace, the reverse of [`quote_arg`].
+///
+/// Supports double and single quotes and backslash escapes like `env -S`, but no variables.
+fn split_args(s: &str) -> Vec<String> {
+    let mut res = Vec::new();
+    let mut cur: Option<String> = None;
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            c if c.is_whitespace() => res.extend(cur.take()),
+            '"' => {
+                let word = cur.get_or_insert_with(String::new);
+                while let Some(c) = chars.next() {
+                    match c {
+                        '"' => break,
+                        '\\' => match chars.next() {
+                            Some('n') => word.push('\n'),
+                            Some('t') => word.push('\t'),
+                            Some(c) => word.push(c),
+                            None => {}
+                        },
+                        c => word.push(c),
+                    }
+                }
+            }
+            '\'' => {
+                let word = cur.get_or_insert_with(String::new);
+                while let Some(c) = chars.next() {
+                    match c {
+                        '\'' => break,
+                        '\\' => match chars.next() {
+                            Some(c @ ('\\' | '\'')) => word.push(c),
+                            Some(c) => {
+                                word.push('\\');
+                                word.push(c);
+                            }
+                            None => word.push('\\'),
+                        },
+                        c => word.push(c),
+                    }
+                }
+            }
+            '\\' => cur.get_or_insert_with(String::new).extend(chars.next()),
+            c => cur.get_or_insert_with(String::new).push(c),
+        }
+    }
+    res.extend(cur);
+    res
+}
+
+/// Split `KEY=VALUE` into key and value if the key is a valid variable name.
+fn parse_env_assignment(word: &str) -> Option<(String, String)> {
+    let (k, v) = word.split_once('=')?;
+    let valid = k
+        .chars()
+        .next()
+        .map(|c| !c.is_ascii_digit())
+        .unwrap_or_default()
+        && k.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+    valid.then(|| (k.to_string(), v.to_string()))
+}
+
 /// Shuffle the list while retaining order inside a batch.
 pub fn shuffle_in_batches(tests: &mut [&str], batch_size: usize) {
     // Tests within a batch should be in the same order as before
@@ -1082,28 +1784,143 @@ pub fn shuffle_in_batches(tests: &mut [&str], batch_size: usize) {
     }
 }
 
+/// Time since `start` as [`Duration`], measured with a monotonic clock.
+fn elapsed_since(start: Instant) -> Duration {
+    start.elapsed().try_into().unwrap_or(Duration::MAX)
+}
+
+/// Resolves once `cancel` is set to `true`.
+///
+/// Never resolves if the sender is dropped without cancelling.
+async fn wait_for_cancel(mut cancel: tokio::sync::watch::Receiver<bool>) {
+    while !*cancel.borrow() {
+        if cancel.changed().await.is_err() {
+            future::pending::<()>().await;
+        }
+    }
+}
+
+/// Write tests with one test per line, as deqp expects them for `--deqp-caselist-file`.
+pub fn write_test_list<W: Write>(mut w: W, tests: &[&str]) -> std::io::Result<()> {
+    for t in tests {
+        writeln!(w, "{t}")?;
+    }
+    Ok(())
+}
+
+/// Parse comma separated `key: value` pairs with numeric values, e.g. `fps: 59.8, frames: 120`.
+///
+/// Parts that are not such a pair are ignored.
+fn parse_metrics(report: &str) -> HashMap<String, f64> {
+    report
+        .split(',')
+        .filter_map(|part| {
+            let (key, value) = part.split_once(':')?;
+            let key = key.trim();
+            if key.is_empty() || key.contains(char::is_whitespace) {
+                return None;
+            }
+            Some((key.to_string(), value.trim().parse().ok()?))
+        })
+        .collect()
+}
+
+/// Check if an environment variable name matches a pattern from `inherit_env`.
+///
+/// A trailing `*` in the pattern matches any suffix.
+fn env_var_matches(pattern: &str, name: &str) -> bool {
+    if let Some(prefix) = pattern.strip_suffix('*') {
+        name.starts_with(prefix)
+    } else {
+        name == pattern
+    }
+}
+
+/// The variables of the current environment that match one of the `inherit_env` patterns.
+fn inherited_env(patterns: &[String]) -> Vec<(OsString, OsString)> {
+    std::env::vars_os()
+        .filter(|(k, _)| {
+            k.to_str()
+                .map(|k| patterns.iter().any(|p| env_var_matches(p, k)))
+                .unwrap_or_default()
+        })
+        .collect()
+}
+
 /// Start a deqp process and parse the output.
 ///
 /// The started process gets killed on drop.
 ///
-/// Returns the pid of the started process and a stream of events.
+/// Returns a stream of events, which also holds the pid and the command line of the started
+/// process.
+///
+/// This keeps the original signature for existing callers. It is the same as
+/// [`run_deqp_with_config`] with only `timeout` and `env` set, other options are only available
+/// through [`RunDeqpConfig`].
 pub fn run_deqp<S: AsRef<OsStr> + std::fmt::Debug>(
     logger: Logger,
     timeout_duration: std::time::Duration,
     args: &[S],
     env: &[(&str, &str)],
+) -> Result<RunDeqpState, DeqpError> {
+    let config = RunDeqpConfig {
+        timeout: timeout_duration,
+        env: env
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect(),
+        ..Default::default()
+    };
+    run_deqp_with_config(logger, &config, args)
+}
+
+/// Start a deqp process with the given options and parse the output.
+///
+/// The started process gets killed on drop.
+///
+/// Returns a stream of events, which also holds the pid and the command line of the started
+/// process.
+pub fn run_deqp_with_config<S: AsRef<OsStr> + std::fmt::Debug>(
+    logger: Logger,
+    config: &RunDeqpConfig,
+    args: &[S],
 ) -> Result<RunDeqpState, DeqpError> {
     debug!(logger, "Start deqp"; "args" => ?args);
-    let mut cmd = Command::new(&args[0]);
+    let mut cmd;
+    if let Some(work_dir) = &config.work_dir {
+        let program = Path::new(&args[0]);
+        if program.is_relative() && program.components().count() > 1 {
+            let cwd = std::env::current_dir().map_err(DeqpError::SpawnFailed)?;
+            cmd = std::process::Command::new(cwd.join(program));
+        } else {
+            cmd = std::process::Command::new(program);
+        }
+        cmd.current_dir(work_dir);
+    } else {
+        cmd = std::process::Command::new(&args[0]);
+    }
+    if let Some(inherit_env) = &config.inherit_env {
+        cmd.env_clear().envs(inherited_env(inherit_env));
+    }
     cmd.args(&args[1..])
-        .envs(env.iter().cloned())
+        .envs(config.env.iter().cloned())
+        .stdin(Stdio::null())
         .stdout(Stdio::piped())
-        .stderr(Stdio::piped())
-        .kill_on_drop(true);
+        .stderr(Stdio::piped());
+    // Start deqp in its own process group. Ctrl+C in a terminal sends SIGINT to the whole
+    // foreground process group, but running tests should finish on the first one.
+    #[cfg(unix)]
+    std::os::unix::process::CommandExt::process_group(&mut cmd, 0);
+    let mut cmd = Command::from(cmd);
+    cmd.kill_on_drop(true);
 
     trace!(logger, "Run deqp"; "args" => ?args);
     let child = cmd.spawn().map_err(DeqpError::SpawnFailed)?;
-    RunDeqpState::new(logger, timeout_duration, child)
+    let args = args
+        .iter()
+        .map(|a| a.as_ref().to_string_lossy().into_owned())
+        .collect();
+    RunDeqpState::new(logger, config, args, child)
 }
 
 /// Sort a list of tests into the order that deqp will run them in by running deqp with
@@ -1119,9 +1936,7 @@ pub async fn sort_with_deqp<S: AsRef<OsStr>>(
 ) -> Result<Vec<String>, DeqpSortError> {
     // Create a temporary file for the input test list
     let mut temp_file = NamedTempFile::new().map_err(DeqpSortError::TempFile)?;
-    for t in tests {
-        writeln!(&mut temp_file, "{t}").map_err(DeqpSortError::WriteFailed)?;
-    }
+    write_test_list(&mut temp_file, tests).map_err(DeqpSortError::WriteFailed)?;
 
     let mut args = args.iter().map(|s| s.as_ref()).collect::<Vec<_>>();
     args.push(temp_file.path().as_os_str());
@@ -1202,16 +2017,20 @@ impl Stream for RunDeqpState {
                     Pin::new(&mut Box::pin(self.child.as_mut().unwrap().wait())).poll(ctx)
                 {
                     Some(match r {
-                        Ok(status) if status.success() => Ok(()),
-                        Ok(status) => Err(DeqpError::Crash {
-                            exit_status: status.code(),
-                        }),
-                        Err(e) => Err(DeqpError::WaitFailed(e)),
+                        Ok(status) if status.success() => (Some(status), Ok(())),
+                        Ok(status) => (
+                            Some(status),
+                            Err(DeqpError::Crash {
+                                exit_status: status.code(),
+                            }),
+                        ),
+                        Err(e) => (None, Err(DeqpError::WaitFailed(e))),
                     })
                 } else {
                     None
                 };
-                if let Some(res) = res {
+                if let Some((status, res)) = res {
+                    self.exit_status = status;
                     self.finished_result = Some(res);
                     self.finished = true;
                     continue;
@@ -1221,22 +2040,28 @@ impl Stream for RunDeqpState {
                     debug!(self.logger, "Detected timeout");
                     self.has_timeout = true;
                     self.finished_result = Some(Err(DeqpError::Timeout));
-                    // Kill deqp
-                    let logger = self.logger.clone();
-                    let mut child = self.child.take().unwrap();
-                    tokio::spawn(async move {
-                        if let Err(e) = child.kill().await {
-                            error!(logger, "Failed to kill deqp after timeout"; "error" => %e);
-                        }
-                    });
+                    self.kill();
+                    self.finish_stderr();
                     return Poll::Ready(None);
                 }
+
+                if let Some(cancel) = &mut self.cancel {
+                    if cancel.as_mut().poll(ctx).is_ready() {
+                        debug!(self.logger, "Cancelled");
+                        self.cancel = None;
+                        self.finished_result = Some(Err(DeqpError::Cancelled));
+                        self.kill();
+                        self.finish_stderr();
+                        return Poll::Ready(None);
+                    }
+                }
             }
 
             if self.stdout_finished && self.stderr_finished && self.finished {
                 if self.has_fatal_error {
                     self.finished_result = Some(Err(DeqpError::DeqpFatalError));
                 }
+                self.finish_stderr();
                 return Poll::Ready(None);
             }
             break Poll::Pending;
@@ -1269,7 +2094,18 @@ pub fn run_test_list<'a, 'list>(
                         return;
                     }
                 };
-                match run_deqp(state.logger.clone(), options.timeout, &args, &[]) {
+                let config = RunDeqpConfig {
+                    work_dir: state.work_dir.as_ref().map(|d| d.path().to_path_buf()),
+                    timeout: options.timeout,
+                    env: options.env.clone(),
+                    inherit_env: options.inherit_env.clone(),
+                    result_prefix: options.result_prefix.clone(),
+                    done_markers: options.done_markers.clone(),
+                    parse_metrics: options.parse_metrics,
+                    stderr_progress: options.stderr_progress,
+                    ..Default::default()
+                };
+                match run_deqp_with_config(state.logger.clone(), &config, &args) {
                     Ok(r) => state.running = Some(r),
                     Err(e) => {
                         yield_!(RunTestListEvent::DeqpError(DeqpErrorWithOutput {
@@ -1300,6 +2136,9 @@ pub fn run_test_list<'a, 'list>(
                             yield_!(r);
                         }
                     }
+                    DeqpEvent::OutputBuffered { stdout_len } => {
+                        trace!(state.logger, "Buffered output"; "stdout_len" => stdout_len);
+                    }
                 },
             }
         }
@@ -1377,6 +2216,18 @@ pub async fn run_tests_parallel<'a>(
             pending_jobs.clear();
         }
 
+        if options
+            .shutdown
+            .as_ref()
+            .map(|s| *s.borrow())
+            .unwrap_or_default()
+            && !pending_jobs.is_empty()
+        {
+            info!(logger, "Shutting down, skip remaining jobs";
+                "skipped_jobs" => pending_jobs.len());
+            pending_jobs.clear();
+        }
+
         while job_executor.len() < job_count {
             if let Some(job) = pending_jobs.pop_front() {
                 let logger = logger.new(o!("job" => job_id));
@@ -1530,10 +2381,33 @@ mod tests {
         slog::Logger::root(drain, o!())
     }
 
-    async fn check_tests(args: &[&str], expected: &[(&str, TestResultType)]) -> Result<()> {
-        check_tests_with_summary(args, expected, |_| {}).await
-    }
-
+    pub(crate) fn create_options(args: &[&str]) -> RunOptions {
+        RunOptions {
+            args: args.iter().map(|s| s.to_string()).collect(),
+            capture_dumps: true,
+            timeout: std::time::Duration::from_secs(2),
+            max_failures: 0,
+            fail_dir: None,
+            retry: true,
+            retry_results: Vec::new(),
+            batch_size: BATCH_SIZE,
+            detect_oom: false,
+            inherit_env: None,
+            env: Vec::new(),
+            extra_args: Vec::new(),
+            work_dir: None,
+            result_prefix: Some("  ".into()),
+            done_markers: vec!["DONE!".into()],
+            parse_metrics: false,
+            stderr_progress: false,
+            shutdown: None,
+        }
+    }
+
+    async fn check_tests(args: &[&str], expected: &[(&str, TestResultType)]) -> Result<()> {
+        check_tests_with_summary(args, expected, |_| {}).await
+    }
+
     async fn check_tests_with_summary<F: for<'a> FnOnce(Summary<'a>)>(
         args: &[&str],
         expected: &[(&str, TestResultType)],
@@ -1558,13 +2432,9 @@ mod tests {
         batch_size: usize,
     ) -> Result<()> {
         let run_options = RunOptions {
-            args: args.iter().map(|s| s.to_string()).collect(),
-            capture_dumps: true,
-            timeout: std::time::Duration::from_secs(2),
-            max_failures: 0,
-            fail_dir: None,
             retry,
             batch_size,
+            ..create_options(args)
         };
 
         let mut summary = Summary::default();
@@ -1922,6 +2792,39 @@ mod tests {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn test_retry_results() -> Result<()> {
+        // a fails and b crashes, every time
+        let script = "for t in $(cat \"$1\"); do echo \"Test case '$t'..\"; \
+                      case $t in a) echo '  Fail (Bad)';; b) exit 1;; esac; done; echo DONE!";
+        let tests = ["a", "b"];
+        let logger = create_logger();
+
+        for (retry_results, a_retried) in [(vec![], true), (vec![TestResultType::Crash], false)] {
+            let run_options = RunOptions {
+                retry_results,
+                ..create_options(&["sh", "-c", script, "sh"])
+            };
+            let mut summary = Summary::default();
+            run_tests_parallel(&logger, &tests, &mut summary, &run_options, None, 1, None).await;
+
+            let a = &summary.0.get("a").expect("Test a has no result").0;
+            let b = &summary.0.get("b").expect("Test b has no result").0;
+            assert_eq!(a.result, TestResultType::Fail);
+            assert_eq!(b.result, TestResultType::Crash);
+            // The first run reports a and b, a retry updates the result id
+            assert_eq!(
+                a.run_id != Some(0),
+                a_retried,
+                "Unexpected run id {:?}",
+                a.run_id
+            );
+            assert_ne!(b.run_id, Some(1), "Crash was not retried");
+        }
+
+        Ok(())
+    }
+
     #[tokio::test]
     async fn test_no_retry() -> Result<()> {
         let logger = create_logger();
@@ -1967,4 +2870,990 @@ mod tests {
 
         Ok(())
     }
+
+    #[tokio::test]
+    async fn test_shutdown() -> Result<()> {
+        let batch_size = 2;
+        let tests = (0..10).map(|i| i.to_string()).collect::<Vec<_>>();
+        let tests = tests.iter().map(|t| t.as_str()).collect::<Vec<_>>();
+
+        let (shutdown_sender, shutdown) = tokio::sync::watch::channel(false);
+        let run_options = RunOptions {
+            batch_size,
+            shutdown: Some(shutdown),
+            ..create_options(&["test/test-delay.sh", "1", "test/test-sorted.sh"])
+        };
+
+        let logger = create_logger();
+        let mut summary = Summary::default();
+        let run = run_tests_parallel(&logger, &tests, &mut summary, &run_options, None, 1, None);
+        let shutdown = async {
+            // Shutdown while the first job is running
+            tokio::time::sleep(std::time::Duration::from_millis(500)).await;
+            shutdown_sender.send(true).unwrap();
+        };
+        future::join(run, shutdown).await;
+
+        // Only the first job ran to completion
+        assert_eq!(summary.0.len(), batch_size, "Result length does not match");
+        for t in &tests[..batch_size] {
+            let r = summary.0.get(t).expect("Test from first job has no result");
+            assert_eq!(
+                r.0.result,
+                TestResultType::Pass,
+                "Test result does not match for test {t}"
+            );
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_inherit_env() -> Result<()> {
+        let logger = create_logger();
+        let inherit_env = ["PATH".to_string()];
+        let config = RunDeqpConfig {
+            timeout: std::time::Duration::from_secs(2),
+            env: vec![("DEQP_RUNNER_TEST".into(), "1".into())],
+            inherit_env: Some(inherit_env.to_vec()),
+            ..Default::default()
+        };
+        let mut state = run_deqp_with_config(logger, &config, &["env"])?;
+        while state.next().await.is_some() {}
+        assert!(matches!(state.finished_result, Some(Ok(()))));
+
+        let vars = state
+            .stdout
+            .lines()
+            .filter_map(|l| l.split_once('=').map(|(k, _)| k))
+            .collect::<Vec<_>>();
+        assert_eq!(vars.len(), 2, "Unexpected environment: {vars:?}");
+        assert!(vars.contains(&"PATH"));
+        assert!(vars.contains(&"DEQP_RUNNER_TEST"));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_device_selection() -> Result<()> {
+        let script = "echo \"Test case 'a'..\"; echo \"  Pass ($2 $VK_ICD_FILENAMES)\"; echo DONE!";
+        let run_options = RunOptions {
+            env: vec![("VK_ICD_FILENAMES".into(), "/icd.json".into())],
+            extra_args: vec!["--deqp-vk-device-id=1".into()],
+            ..create_options(&["sh", "-c", script, "sh"])
+        };
+
+        let mut results = Vec::new();
+        let mut events = run_test_list(create_logger(), &["a"], &run_options);
+        while let Some(e) = events.next().await {
+            if let RunTestListEvent::TestResult(res) = e {
+                results.push(res.data.result);
+            }
+        }
+        assert_eq!(results.len(), 1, "Result length does not match");
+        assert_eq!(results[0].variant, TestResultType::Pass);
+        assert_eq!(results[0].stdout, "--deqp-vk-device-id=1 /icd.json");
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_work_dir() -> Result<()> {
+        let work_dir = tempfile::tempdir()?;
+        let config = RunDeqpConfig {
+            work_dir: Some(work_dir.path().to_path_buf()),
+            ..Default::default()
+        };
+        // A relative path to the executable still works
+        let mut state = run_deqp_with_config(
+            create_logger(),
+            &config,
+            &["test/test-delay.sh", "0", "pwd"],
+        )?;
+        while state.next().await.is_some() {}
+        assert!(matches!(state.finished_result, Some(Ok(()))));
+        assert_eq!(
+            Path::new(state.stdout.trim()).canonicalize()?,
+            work_dir.path().canonicalize()?
+        );
+
+        // Every process runs in its own directory
+        let script = "for t in $(cat \"$1\"); do echo \"Test case '$t'..\"; \
+                      echo \"  Pass ($(pwd))\"; done; echo DONE!";
+        let run_options = RunOptions {
+            batch_size: 1,
+            work_dir: Some(work_dir.path().to_path_buf()),
+            ..create_options(&["sh", "-c", script, "sh"])
+        };
+        let logger = create_logger();
+        let mut summary = Summary::default();
+        run_tests_parallel(
+            &logger,
+            &["a", "b"],
+            &mut summary,
+            &run_options,
+            None,
+            2,
+            None,
+        )
+        .await;
+        let dirs = ["a", "b"]
+            .iter()
+            .map(|t| {
+                let r = summary.0.get(t).expect("Test has no result");
+                assert_eq!(r.0.result, TestResultType::Pass);
+                PathBuf::from(&r.1.as_ref().unwrap().result.stdout)
+            })
+            .collect::<Vec<_>>();
+        assert_ne!(dirs[0], dirs[1]);
+        for d in &dirs {
+            assert_eq!(
+                d.parent().unwrap().canonicalize()?,
+                work_dir.path().canonicalize()?
+            );
+            assert!(!d.exists(), "Working directory {:?} was not removed", d);
+        }
+
+        // The directory of a failed process is kept in the failure directory
+        let fail_dir = tempfile::tempdir()?;
+        let script = "echo \"Test case 'c'..\"; touch output.txt; echo '  Fail (Bad)'; \
+                      echo DONE!";
+        let run_options = RunOptions {
+            retry: false,
+            fail_dir: Some(fail_dir.path().to_path_buf()),
+            work_dir: Some(work_dir.path().to_path_buf()),
+            ..create_options(&["sh", "-c", script])
+        };
+        let mut summary = Summary::default();
+        run_tests_parallel(&logger, &["c"], &mut summary, &run_options, None, 1, None).await;
+        let r = summary.0.get("c").expect("Test has no result");
+        assert_eq!(r.0.result, TestResultType::Fail);
+        assert!(fail_dir
+            .path()
+            .join("c")
+            .join(WORK_DIR)
+            .join("output.txt")
+            .exists());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_reproduce_list() -> Result<()> {
+        let fail_dir = tempfile::tempdir()?;
+        let run_options = RunOptions {
+            retry: false,
+            fail_dir: Some(fail_dir.path().to_path_buf()),
+            env: vec![("VK_ICD_FILENAMES".into(), "/tmp/icd.json".into())],
+            extra_args: vec!["--deqp-vk-device-id=2".into()],
+            ..create_options(&["sh", "-c", "echo \"Test case 'a'..\"; echo '  Fail (Bad)'"])
+        };
+        let logger = create_logger();
+        let mut summary = Summary::default();
+        run_tests_parallel(&logger, &["a"], &mut summary, &run_options, None, 1, None).await;
+
+        let list = std::fs::read_to_string(fail_dir.path().join("a").join(TEST_LIST_FILE))?;
+        let mut lines = list.lines();
+        assert_eq!(
+            lines.next(),
+            Some(
+                "#!/usr/bin/env -S VK_ICD_FILENAMES=/tmp/icd.json sh -c \
+                 \"echo \\\"Test case 'a'..\\\"; echo '  Fail (Bad)'\""
+            )
+        );
+        assert_eq!(
+            lines.next(),
+            Some("# Arguments after the test list: --deqp-vk-device-id=2")
+        );
+        assert_eq!(parse_test_file(&list), ["a"]);
+        let cmd = RunCommand::parse(&list).expect("No command in reproduce list");
+        assert_eq!(cmd.args, run_options.args);
+        assert_eq!(cmd.env, run_options.env);
+        assert_eq!(cmd.extra_args, run_options.extra_args);
+        assert!(!cmd.clean_env);
+
+        // A clean environment is recorded with the values of the inherited variables
+        let fail_dir = tempfile::tempdir()?;
+        let run_options = RunOptions {
+            fail_dir: Some(fail_dir.path().to_path_buf()),
+            inherit_env: Some(vec!["PATH".into()]),
+            ..run_options
+        };
+        let mut summary = Summary::default();
+        run_tests_parallel(&logger, &["a"], &mut summary, &run_options, None, 1, None).await;
+
+        let list = std::fs::read_to_string(fail_dir.path().join("a").join(TEST_LIST_FILE))?;
+        assert!(
+            list.starts_with("#!/usr/bin/env -S -i PATH="),
+            "Unexpected list: {}",
+            list
+        );
+        let cmd = RunCommand::parse(&list).expect("No command in reproduce list");
+        assert!(cmd.clean_env);
+        assert_eq!(
+            cmd.env,
+            [
+                ("PATH".to_string(), std::env::var("PATH")?),
+                ("VK_ICD_FILENAMES".to_string(), "/tmp/icd.json".to_string()),
+            ]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_run_command() {
+        let cmd = RunCommand {
+            args: vec![
+                "deqp-vk".into(),
+                "--deqp-log-filename=/tmp/my logs/TestResults.qpa".into(),
+                "line 1\nline 2 with \"quotes\", 'apostrophes', $VAR and \\".into(),
+                String::new(),
+            ],
+            env: vec![
+                ("VK_ICD_FILENAMES".into(), "/opt/my driver/icd.json".into()),
+                ("MESA_DEBUG".into(), "1".into()),
+            ],
+            clean_env: true,
+            extra_args: vec!["--deqp-vk-device-id=2".into(), "#1".into()],
+        };
+        let mut list = Vec::new();
+        cmd.write(&mut list).unwrap();
+        write_test_list(&mut list, &["a", "b"]).unwrap();
+        let list = String::from_utf8(list).unwrap();
+
+        assert_eq!(list.lines().count(), 4, "Unexpected list: {}", list);
+        assert_eq!(RunCommand::parse(&list), Some(cmd));
+        assert_eq!(parse_test_file(&list), ["a", "b"]);
+
+        // Without any special characters, the command is written as is
+        let cmd = RunCommand {
+            args: vec!["/usr/bin/deqp-vk".into()],
+            ..Default::default()
+        };
+        let mut list = Vec::new();
+        cmd.write(&mut list).unwrap();
+        let list = String::from_utf8(list).unwrap();
+        assert_eq!(list, "#!/usr/bin/deqp-vk\n");
+        assert_eq!(RunCommand::parse(&list), Some(cmd));
+
+        assert_eq!(RunCommand::parse("a\nb\n"), None);
+    }
+
+    #[test]
+    fn test_env_var_matches() {
+        assert!(env_var_matches("DISPLAY", "DISPLAY"));
+        assert!(!env_var_matches("DISPLAY", "DISPLAY2"));
+        assert!(env_var_matches("MESA_*", "MESA_DEBUG"));
+        assert!(env_var_matches("MESA_*", "MESA_"));
+        assert!(!env_var_matches("MESA_*", "RADV_DEBUG"));
+    }
+
+    #[tokio::test]
+    async fn test_run_deqp_config() -> Result<()> {
+        let config = RunDeqpConfig {
+            timeout: std::time::Duration::from_millis(500),
+            env: vec![("DEQP_RUNNER_TEST".into(), "OK".into())],
+            result_prefix: Some("> ".into()),
+            done_markers: vec!["END".into()],
+            ..Default::default()
+        };
+        let script = "echo \"Test case 'a'..\"; echo \"> Pass ($DEQP_RUNNER_TEST)\"; echo END; \
+                      echo \"> Fail (After end)\"; sleep 10";
+        let mut state = run_deqp_with_config(create_logger(), &config, &["sh", "-c", script])?;
+
+        let start = Instant::now();
+        let results = collect_results(&mut state).await;
+        assert_eq!(results.len(), 1, "Unexpected results: {:?}", results);
+        assert_eq!(results[0].variant, TestResultType::Pass);
+        assert_eq!(results[0].stdout, "OK");
+        assert!(matches!(
+            state.finished_result,
+            Some(Err(DeqpError::Timeout))
+        ));
+        assert!(start.elapsed() < std::time::Duration::from_secs(5));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_run_deqp_env() -> Result<()> {
+        // The original signature sets timeout and environment
+        let mut state = run_deqp(
+            create_logger(),
+            std::time::Duration::from_secs(2),
+            &[
+                "sh",
+                "-c",
+                "echo \"Test case 'a'..\"; echo \"  Pass ($DEQP_RUNNER_TEST)\"",
+            ],
+            &[("DEQP_RUNNER_TEST", "OK")],
+        )?;
+        let results = collect_results(&mut state).await;
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0].stdout, "OK");
+        assert!(matches!(state.finished_result, Some(Ok(()))));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_cancel() -> Result<()> {
+        let logger = create_logger();
+        let (cancel_sender, cancel) = tokio::sync::watch::channel(false);
+        // Prints some tests and then hangs
+        let config = RunDeqpConfig {
+            timeout: std::time::Duration::from_secs(30),
+            cancel: Some(cancel),
+            ..Default::default()
+        };
+        let mut state = run_deqp_with_config(
+            logger,
+            &config,
+            &["test/test-timeout.sh", "logs/a", "/dev/null", "0"],
+        )?;
+
+        let start = Instant::now();
+        let mut results = 0;
+        while let Some(e) = state.next().await {
+            if let DeqpEvent::TestEnd { .. } = e {
+                results += 1;
+                cancel_sender.send(true)?;
+            }
+        }
+        assert!(results > 0, "No test finished before cancelling");
+        assert!(matches!(
+            state.finished_result,
+            Some(Err(DeqpError::Cancelled))
+        ));
+        assert!(start.elapsed() < std::time::Duration::from_secs(10));
+
+        Ok(())
+    }
+
+    /// Start a fake deqp process that prints `stdout`.
+    ///
+    /// The returned file needs to be kept alive until the process exits.
+    fn run_with_output(stdout: &str) -> Result<(NamedTempFile, RunDeqpState)> {
+        let config = RunDeqpConfig {
+            timeout: std::time::Duration::from_secs(2),
+            ..Default::default()
+        };
+        run_with_config(stdout, &config)
+    }
+
+    /// Same as [`run_with_output`] but with custom options.
+    fn run_with_config(
+        stdout: &str,
+        config: &RunDeqpConfig,
+    ) -> Result<(NamedTempFile, RunDeqpState)> {
+        let mut file = NamedTempFile::new()?;
+        file.write_all(stdout.as_bytes())?;
+        let path = file.path().to_str().unwrap().to_string();
+        let state = run_deqp_with_config(create_logger(), config, &["cat", &path])?;
+        Ok((file, state))
+    }
+
+    async fn collect_results(state: &mut RunDeqpState) -> Vec<TestResult> {
+        let mut results = Vec::new();
+        while let Some(e) = state.next().await {
+            if let DeqpEvent::TestEnd { result } = e {
+                results.push(result);
+            }
+        }
+        results
+    }
+
+    #[tokio::test]
+    async fn test_result_prefix() -> Result<()> {
+        let output = "Test case 'a'..\n    Pass (OK)\nTest case 'b'..\n    Fail (Bad)\nDONE!\n";
+
+        // Not parsed with the default prefix
+        let (_file, mut state) = run_with_output(output)?;
+        assert!(collect_results(&mut state).await.is_empty());
+
+        let config = RunDeqpConfig {
+            result_prefix: Some("    ".into()),
+            ..Default::default()
+        };
+        let (_file, mut state) = run_with_config(output, &config)?;
+        let results = collect_results(&mut state).await;
+        let variants = results.iter().map(|r| &r.variant).collect::<Vec<_>>();
+        assert_eq!(variants, [&TestResultType::Pass, &TestResultType::Fail]);
+
+        let config = RunDeqpConfig {
+            result_prefix: None,
+            ..Default::default()
+        };
+        let (_file, mut state) = run_with_config(output, &config)?;
+        let results = collect_results(&mut state).await;
+        let variants = results.iter().map(|r| &r.variant).collect::<Vec<_>>();
+        assert_eq!(variants, [&TestResultType::Pass, &TestResultType::Fail]);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_no_done_marker() -> Result<()> {
+        // A single test that passes and a clean exit is enough, even without DONE!
+        let run_options = create_options(&[
+            "sh",
+            "-c",
+            "echo \"Test case 'a'..\"; echo '  Pass (OK)'",
+            "sh",
+        ]);
+
+        let mut results = Vec::new();
+        let mut events = run_test_list(create_logger(), &["a"], &run_options);
+        while let Some(e) = events.next().await {
+            match e {
+                RunTestListEvent::TestResult(res) => results.push(res.data.result.variant),
+                RunTestListEvent::DeqpError(e) => panic!("Unexpected error: {:?}", e),
+            }
+        }
+        assert_eq!(results, [TestResultType::Pass]);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_no_tests_run() -> Result<()> {
+        /// Run deqp with this script and return the error.
+        async fn run_script(script: &str, timeout: std::time::Duration) -> DeqpError {
+            let run_options = RunOptions {
+                timeout,
+                ..create_options(&["sh", "-c", script, "sh"])
+            };
+            let mut errors = Vec::new();
+            let mut events = run_test_list(create_logger(), &["a", "b"], &run_options);
+            while let Some(e) = events.next().await {
+                if let RunTestListEvent::DeqpError(e) = e {
+                    errors.push(e.error);
+                }
+            }
+            assert_eq!(errors.len(), 1, "Unexpected errors: {:?}", errors);
+            errors.remove(0)
+        }
+        let timeout = std::time::Duration::from_secs(2);
+
+        let error = run_script(
+            "echo 'Initializing'; echo 'Failed to create instance' >&2; exit 3",
+            timeout,
+        )
+        .await;
+        match &error {
+            DeqpError::NoTestsRun {
+                exit_status,
+                end,
+                stderr,
+            } => {
+                assert_eq!(*exit_status, Some(3));
+                assert_eq!(*end, ProcessEnd::Exited);
+                assert_eq!(stderr, "Failed to create instance");
+            }
+            e => panic!("Unexpected error: {:?}", e),
+        }
+        assert_eq!(
+            error.to_string(),
+            "deqp did not run any tests (exit status 3), stderr:\nFailed to create instance"
+        );
+
+        // The exit code is kept when deqp reports a fatal error
+        let error = run_script("echo 'FATAL ERROR: x' >&2; exit 1", timeout).await;
+        assert!(
+            matches!(
+                error,
+                DeqpError::NoTestsRun {
+                    exit_status: Some(1),
+                    end: ProcessEnd::Exited,
+                    ..
+                }
+            ),
+            "Unexpected error: {:?}",
+            error
+        );
+        assert_eq!(
+            error.to_string(),
+            "deqp did not run any tests (exit status 1), stderr:\nFATAL ERROR: x"
+        );
+
+        let error = run_script("kill -KILL $$", timeout).await;
+        assert_eq!(
+            error.to_string(),
+            "deqp did not run any tests (killed by signal)"
+        );
+
+        let error = run_script("sleep 10", std::time::Duration::from_millis(100)).await;
+        assert_eq!(error.to_string(), "deqp did not run any tests (timed out)");
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_summary() -> Result<()> {
+        // Crash in b, deqp is restarted for c and d
+        let script = "for t in $(cat \"$1\"); do echo \"Test case '$t'..\"; \
+                      if [ $t = b ]; then exit 1; fi; \
+                      if [ $t = d ]; then echo '  Fail (Bad)'; else echo '  Pass (OK)'; fi; \
+                      done; echo DONE!";
+        let run_options = create_options(&["sh", "-c", script, "sh"]);
+        let tests = ["a", "b", "c", "d"];
+
+        let mut summaries = Vec::new();
+        let mut events = run_test_list(create_logger(), &tests, &run_options);
+        while let Some(e) = events.next().await {
+            if let RunTestListEvent::TestResult(r) = e {
+                let summary = r.summary();
+                assert_eq!(summary.name, r.data.name);
+                assert_eq!(summary.variant, r.data.result.variant);
+                assert_eq!(summary.duration, r.data.duration);
+                assert_eq!(tests[summary.index], r.data.name);
+                summaries.push(summary);
+            }
+        }
+        let summaries = summaries
+            .iter()
+            .map(|s| (s.index, &s.variant))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            summaries,
+            [
+                (0, &TestResultType::Pass),
+                (1, &TestResultType::Crash),
+                (2, &TestResultType::Pass),
+                (3, &TestResultType::Fail),
+            ]
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_args() -> Result<()> {
+        let args = ["test/test-delay.sh", "0", "echo", "a b"];
+        let mut state = run_deqp(
+            create_logger(),
+            std::time::Duration::from_secs(2),
+            &args,
+            &[],
+        )?;
+        assert_eq!(state.args, args);
+        while state.next().await.is_some() {}
+        assert!(matches!(state.finished_result, Some(Ok(()))));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_stderr_progress() -> Result<()> {
+        let args = [
+            "sh",
+            "-c",
+            "for i in 1 2 3 4 5 6 7 8; do echo \"Compiling $i\" >&2; sleep 0.1; done",
+        ];
+        let timeout = std::time::Duration::from_millis(300);
+
+        let mut state = run_deqp(create_logger(), timeout, &args, &[])?;
+        while state.next().await.is_some() {}
+        assert!(matches!(
+            state.finished_result,
+            Some(Err(DeqpError::Timeout))
+        ));
+
+        let config = RunDeqpConfig {
+            timeout,
+            stderr_progress: true,
+            ..Default::default()
+        };
+        let mut state = run_deqp_with_config(create_logger(), &config, &args)?;
+        while state.next().await.is_some() {}
+        assert!(matches!(state.finished_result, Some(Ok(()))));
+
+        Ok(())
+    }
+
+    /// Run the tests with [`run_test_list`] and collect the results.
+    async fn collect_list_results(tests: &[&str], run_options: &RunOptions) -> Vec<TestResult> {
+        let mut results = Vec::new();
+        let mut events = run_test_list(create_logger(), tests, run_options);
+        while let Some(e) = events.next().await {
+            if let RunTestListEvent::TestResult(res) = e {
+                results.push(res.data.result);
+            }
+        }
+        results
+    }
+
+    /// Check that options are forwarded from [`RunOptions`] to every deqp process.
+    #[tokio::test]
+    async fn test_forwarded_options() -> Result<()> {
+        let prefix_script = "echo \"Test case 'a'..\"; echo '    Pass (OK)'; echo DONE!";
+        let done_script = "echo \"Test case 'a'..\"; echo '  Pass (OK)'; echo 'ALL DONE'; \
+                           echo \"Test case 'b'..\"; echo '  Fail (Not a result)'";
+        let metrics_script = "echo \"Test case 'a'..\"; echo '  Pass (fps: 59.8)'";
+        let stderr_script = "echo \"Test case 'a'..\"; \
+                             for i in 1 2 3 4 5 6 7 8; do echo \"Compiling $i\" >&2; sleep 0.1; done; \
+                             echo '  Pass (OK)'";
+        let short_timeout = std::time::Duration::from_millis(300);
+
+        let cases = [
+            (
+                "result_prefix: two spaces",
+                create_options(&["sh", "-c", prefix_script]),
+                TestResultType::Crash,
+                None,
+            ),
+            (
+                "result_prefix: four spaces",
+                RunOptions {
+                    result_prefix: Some("    ".into()),
+                    ..create_options(&["sh", "-c", prefix_script])
+                },
+                TestResultType::Pass,
+                None,
+            ),
+            (
+                "result_prefix: any",
+                RunOptions {
+                    result_prefix: None,
+                    ..create_options(&["sh", "-c", prefix_script])
+                },
+                TestResultType::Pass,
+                None,
+            ),
+            (
+                "done_markers",
+                RunOptions {
+                    done_markers: vec!["ALL DONE".into()],
+                    ..create_options(&["sh", "-c", done_script])
+                },
+                TestResultType::Pass,
+                None,
+            ),
+            (
+                "parse_metrics: false",
+                create_options(&["sh", "-c", metrics_script]),
+                TestResultType::Pass,
+                None,
+            ),
+            (
+                "parse_metrics: true",
+                RunOptions {
+                    parse_metrics: true,
+                    ..create_options(&["sh", "-c", metrics_script])
+                },
+                TestResultType::Pass,
+                Some(59.8),
+            ),
+            (
+                "stderr_progress: false",
+                RunOptions {
+                    timeout: short_timeout,
+                    ..create_options(&["sh", "-c", stderr_script])
+                },
+                TestResultType::Timeout,
+                None,
+            ),
+            (
+                "stderr_progress: true",
+                RunOptions {
+                    timeout: short_timeout,
+                    stderr_progress: true,
+                    ..create_options(&["sh", "-c", stderr_script])
+                },
+                TestResultType::Pass,
+                None,
+            ),
+        ];
+        for (name, run_options, expected, fps) in &cases {
+            let results = collect_list_results(&["a"], run_options).await;
+            let variants = results.iter().map(|r| &r.variant).collect::<Vec<_>>();
+            assert_eq!(variants, [expected], "{}", name);
+            assert_eq!(results[0].metrics.get("fps"), fps.as_ref(), "{}", name);
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_metrics() -> Result<()> {
+        let output = "Test case 'a'..\n  Pass (fps: 59.8)\n\
+                      Test case 'b'..\n  NotSupported (Format not supported: VK_FORMAT_R8_UNORM)\n\
+                      DONE!\n";
+
+        // Not parsed by default
+        let (_file, mut state) = run_with_output(output)?;
+        let results = collect_results(&mut state).await;
+        assert!(results[0].metrics.is_empty());
+
+        let config = RunDeqpConfig {
+            parse_metrics: true,
+            ..Default::default()
+        };
+        let (_file, mut state) = run_with_config(output, &config)?;
+        let results = collect_results(&mut state).await;
+        assert_eq!(results.len(), 2);
+        assert_eq!(results[0].stdout, "fps: 59.8");
+        assert_eq!(results[0].metrics.get("fps"), Some(&59.8));
+        assert!(results[1].metrics.is_empty());
+        assert_eq!(
+            results[1].reason.as_deref(),
+            Some("Format not supported: VK_FORMAT_R8_UNORM")
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_write_test_list() -> Result<()> {
+        let test_file = std::fs::read_to_string("logs/in")?;
+        let tests = parse_test_file(&test_file);
+        let mut list = Vec::new();
+        write_test_list(&mut list, &tests)?;
+        assert_eq!(parse_test_file(std::str::from_utf8(&list)?), tests);
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_metrics() {
+        let metrics = parse_metrics("fps: 59.8, frames: 120, mode: fast, Took 3: seconds");
+        assert_eq!(metrics.len(), 2, "Unexpected metrics: {:?}", metrics);
+        assert_eq!(metrics["fps"], 59.8);
+        assert_eq!(metrics["frames"], 120.0);
+    }
+
+    #[tokio::test]
+    async fn test_duration() -> Result<()> {
+        let test_file = tokio::fs::read_to_string("logs/in").await?;
+        let tests = parse_test_file(&test_file);
+        // Contains a crash, so durations are computed for finished and crashed tests
+        let run_options = create_options(&["test/test-runner.sh", "logs/d", "/dev/null", "0"]);
+
+        let mut results = 0;
+        let mut events = run_test_list(create_logger(), &tests, &run_options);
+        while let Some(e) = events.next().await {
+            if let RunTestListEvent::TestResult(res) = e {
+                results += 1;
+                assert!(
+                    !res.data.duration.is_negative(),
+                    "Negative duration for test {}: {}",
+                    res.data.name,
+                    res.data.duration
+                );
+            }
+        }
+        assert_eq!(results, tests.len(), "Result length does not match");
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_tests_completed() -> Result<()> {
+        let output = tokio::fs::read_to_string("logs/a").await?;
+        let (_file, mut state) = run_with_output(&output)?;
+        let results = collect_results(&mut state).await;
+        assert_eq!(results.len(), 18);
+        assert_eq!(state.tests_completed, 18);
+        assert!(matches!(state.finished_result, Some(Ok(()))));
+
+        // Crashes in the last test
+        let output = tokio::fs::read_to_string("logs/d").await?;
+        let (_file, mut state) = run_with_output(&output)?;
+        collect_results(&mut state).await;
+        assert_eq!(state.tests_completed, 15);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_done_markers() -> Result<()> {
+        let output = "Test case 'a'..\n  Pass (OK)\nALL DONE\n  Fail (Not a result)\n";
+
+        let config = RunDeqpConfig {
+            done_markers: vec!["DONE!".into(), "ALL DONE".into()],
+            ..Default::default()
+        };
+        let (_file, mut state) = run_with_config(output, &config)?;
+        let results = collect_results(&mut state).await;
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0].variant, TestResultType::Pass);
+        assert!(state.tests_done);
+        assert!(matches!(state.finished_result, Some(Ok(()))));
+
+        // Not detected with the default marker
+        let (_file, mut state) = run_with_output(output)?;
+        assert_eq!(collect_results(&mut state).await.len(), 2);
+        assert!(!state.tests_done);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_detect_oom() -> Result<()> {
+        let test_file = tokio::fs::read_to_string("logs/in").await?;
+        let tests = parse_test_file(&test_file);
+        let crashing_test =
+            "dEQP-VK.tessellation.primitive_discard.triangles_fractional_even_spacing_cw_point_mode";
+        let logger = create_logger();
+
+        for (detect_oom, expected) in [
+            (false, TestResultType::Crash),
+            (true, TestResultType::ResourceError),
+        ] {
+            let run_options = RunOptions {
+                detect_oom,
+                ..create_options(&["test/test-runner.sh", "logs/d", "logs/oom-err", "134"])
+            };
+            let mut summary = Summary::default();
+            run_tests_parallel(&logger, &tests, &mut summary, &run_options, None, 1, None).await;
+
+            let res = summary
+                .0
+                .get(crashing_test)
+                .expect("Crashing test has no result");
+            assert_eq!(res.0.result, expected, "Test result does not match");
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_output_buffered() -> Result<()> {
+        let mut output = "Test case 'a'..\n".to_string();
+        let line = format!("{}\n", "x".repeat(99));
+        for _ in 0..(BUFFERED_REPORT_SIZE * 3 / line.len() + 1) {
+            output.push_str(&line);
+        }
+        output.push_str("  Pass (OK)\nDONE!\n");
+
+        let (_file, mut state) = run_with_output(&output)?;
+        let mut buffered = Vec::new();
+        let mut results = 0;
+        while let Some(e) = state.next().await {
+            match e {
+                DeqpEvent::OutputBuffered { stdout_len } => {
+                    assert_eq!(results, 0, "Buffered output reported after the result");
+                    buffered.push(stdout_len);
+                }
+                DeqpEvent::TestEnd { .. } => results += 1,
+                DeqpEvent::TestStart { .. } => {}
+            }
+        }
+        assert_eq!(results, 1);
+        assert_eq!(buffered.len(), 3, "Unexpected reports: {buffered:?}");
+        assert!(buffered.windows(2).all(|w| w[0] < w[1]));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_timeout_kills_child() -> Result<()> {
+        let mut state = run_deqp(
+            create_logger(),
+            std::time::Duration::from_millis(100),
+            &["sleep", "60"],
+            &[],
+        )?;
+        while state.next().await.is_some() {}
+        assert!(matches!(
+            state.finished_result,
+            Some(Err(DeqpError::Timeout))
+        ));
+
+        // The process has to be killed already, even when no other task runs anymore
+        let mut child = state
+            .child
+            .take()
+            .expect("Child is still owned by the state");
+        drop(state);
+        let status =
+            tokio::time::timeout(std::time::Duration::from_secs(5), child.wait()).await??;
+        assert!(!status.success());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_stderr_flood() -> Result<()> {
+        let alternating = "awk 'BEGIN { for (i = 0; i < 20000; i++) print \"A\\nA\\nB\\nB\" }' >&2";
+
+        // Alternating repeated lines after stderr is full
+        let mut state = run_deqp(
+            create_logger(),
+            std::time::Duration::from_secs(10),
+            &["sh", "-c", &format!("seq 1 300000 >&2; {alternating}")],
+            &[],
+        )?;
+        while state.next().await.is_some() {}
+        assert!(matches!(state.finished_result, Some(Ok(()))));
+        assert!(
+            state.stderr.len() < MAX_STDERR_SIZE + 1024,
+            "stderr is {} bytes",
+            state.stderr.len()
+        );
+        assert!(state.stderr.starts_with("1\n2\n"));
+        assert!(!state.stderr.contains("[previous line repeated"));
+        assert!(state.stderr.ends_with(" lines of stderr skipped]\n"));
+
+        // The first fatal error and the lines around it are kept
+        let mut state = run_deqp(
+            create_logger(),
+            std::time::Duration::from_secs(10),
+            &[
+                "sh",
+                "-c",
+                &format!(
+                    "seq 1 300000 >&2; yes 'FATAL ERROR: device lost' | head -n 10000 >&2; \
+                     {alternating}"
+                ),
+            ],
+            &[],
+        )?;
+        while state.next().await.is_some() {}
+        assert!(matches!(
+            state.finished_result,
+            Some(Err(DeqpError::DeqpFatalError))
+        ));
+        assert!(
+            state.stderr.len() < MAX_STDERR_SIZE + 1024,
+            "stderr is {} bytes",
+            state.stderr.len()
+        );
+        assert!(state.stderr.starts_with("1\n2\n"));
+        assert!(state.stderr.contains(
+            " lines of stderr skipped]\n299996\n299997\n299998\n299999\n300000\n\
+             FATAL ERROR: device lost\n[previous line repeated 9999 times]\n\
+             A\n[previous line repeated 1 times]\nB\n[previous line repeated 1 times]\n"
+        ));
+        assert!(state.stderr.ends_with(" lines of stderr skipped]\n"));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_not_supported_reason() -> Result<()> {
+        let output = "Test case 'a'..\n  NotSupported (Format not supported)\n\
+            Test case 'b'..\n  Pass (OK)\nDONE!\n";
+        let (_file, mut state) = run_with_output(output)?;
+        let results = collect_results(&mut state).await;
+        assert_eq!(results.len(), 2);
+        assert_eq!(results[0].variant, TestResultType::NotSupported);
+        assert_eq!(results[0].reason.as_deref(), Some("Format not supported"));
+        assert_eq!(results[1].reason, None);
+
+        let output = "Test case 'a'..\n  QualityWarning (precision lower than required)\n\
+            Test case 'b'..\n  CompatibilityWarning (Uses an old extension)\nDONE!\n";
+        let (_file, mut state) = run_with_output(output)?;
+        let results = collect_results(&mut state).await;
+        assert_eq!(results.len(), 2);
+        assert_eq!(results[0].variant, TestResultType::QualityWarning);
+        assert_eq!(
+            results[0].reason.as_deref(),
+            Some("precision lower than required")
+        );
+        assert_eq!(results[1].variant, TestResultType::CompatibilityWarning);
+        assert_eq!(results[1].reason.as_deref(), Some("Uses an old extension"));
+
+        Ok(())
+    }
 }
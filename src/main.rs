@@ -6,6 +6,9 @@ use clap::Parser;
 use deqp_runner::*;
 use slog::{info, o, Drain};
 
+/// Report this many of the most common reasons for not supported tests.
+const TOP_NOT_SUPPORTED_REASONS: usize = 10;
+
 #[tokio::main(flavor = "current_thread")]
 async fn main() -> Result<()> {
     real_main().await
@@ -82,17 +85,38 @@ async fn real_main() -> Result<()> {
         shuffle_in_batches(&mut tests, BATCH_SIZE);
     }
 
+    if options.list_only {
+        write_test_list(std::io::stdout().lock(), &tests)?;
+        return Ok(());
+    }
+
+    let mut env = Vec::new();
+    let mut extra_args = Vec::new();
+    let mut clean_env = options.clean_env || !options.inherit_env.is_empty();
     if options.run_command.is_empty() {
         // Try to read run command from options in test list file
-        if let Some(cmd) = test_file.strip_prefix("#!").and_then(|l| l.lines().next()) {
-            let cmd = cmd.trim().trim_start_matches("/usr/bin/env -S ");
-            // Split by spaces, we do not want to implement a whole parser
-            options.run_command = cmd.split(' ').map(|s| s.to_string()).collect();
+        if let Some(cmd) = RunCommand::parse(&test_file) {
+            options.run_command = cmd.args;
+            env = cmd.env;
+            extra_args = cmd.extra_args;
+            clean_env |= cmd.clean_env;
         }
     }
 
-    info!(logger, "Running"; "command" => ?options.run_command);
+    info!(logger, "Running"; "command" => ?options.run_command, "env" => ?env,
+        "extra_args" => ?extra_args);
 
+    if let Some(icd) = &options.icd {
+        env.push(("VK_ICD_FILENAMES".to_string(), icd.display().to_string()));
+    }
+    if !options.layer.is_empty() {
+        env.push(("VK_INSTANCE_LAYERS".to_string(), options.layer.join(":")));
+    }
+    if let Some(id) = options.device_id {
+        extra_args.push(format!("--deqp-vk-device-id={id}"));
+    }
+
+    let (shutdown_sender, shutdown) = tokio::sync::watch::channel(false);
     let run_options = RunOptions {
         args: options.run_command,
         capture_dumps: true,
@@ -100,7 +124,30 @@ async fn real_main() -> Result<()> {
         max_failures: options.max_failures,
         fail_dir: Some(options.output.join(FAIL_DIR)),
         retry: !options.no_retry,
+        retry_results: options.retry_only,
         batch_size: BATCH_SIZE,
+        detect_oom: options.detect_oom,
+        inherit_env: if clean_env {
+            Some(options.inherit_env)
+        } else {
+            None
+        },
+        env,
+        extra_args,
+        work_dir: options.work_dir,
+        result_prefix: match options.result_prefix {
+            Some(p) if p.is_empty() => None,
+            Some(p) => Some(p),
+            None => Some("  ".into()),
+        },
+        done_markers: if options.done_marker.is_empty() {
+            vec!["DONE!".into()]
+        } else {
+            options.done_marker
+        },
+        parse_metrics: options.parse_metrics,
+        stderr_progress: options.stderr_progress,
+        shutdown: Some(shutdown),
     };
 
     let progress_bar = if !options.no_progress {
@@ -122,7 +169,13 @@ async fn real_main() -> Result<()> {
             job_count,
             progress_bar,
         ) => {}
-        _ = tokio::signal::ctrl_c() => {
+        _ = async {
+            // The first sigint finishes running jobs, the second one aborts immediately
+            let _ = tokio::signal::ctrl_c().await;
+            info!(logger, "Received sigint, finishing running jobs (press Ctrl+C again to abort)");
+            let _ = shutdown_sender.send(true);
+            let _ = tokio::signal::ctrl_c().await;
+        } => {
             info!(logger, "Killed by sigint");
         }
     }
@@ -181,5 +234,19 @@ async fn real_main() -> Result<()> {
         "not_supported" => not_supported, "fail" => fail, "crash" => crash, "timeout" => timeout,
         "missing" => missing_count, "not_found" => missing.len(), "not_run" => not_run, "flake" => flake);
 
+    let stats = summary::run_stats(&summary);
+    let stats_file = options.output.join(STATS_SUMMARY);
+    std::fs::write(&stats_file, serde_json::to_string(&stats)?)
+        .map_err(|e| format_err!("Failed to write {:?}: {}", stats_file, e))?;
+    info!(logger, "Run time"; "elapsed" => format!("{:.1}s", stats.elapsed.as_seconds_f64()),
+        "tests_per_second" => format!("{:.1}", stats.tests_per_second()));
+
+    for (reason, count) in summary::not_supported_reasons(&summary)
+        .into_iter()
+        .take(TOP_NOT_SUPPORTED_REASONS)
+    {
+        info!(logger, "Not supported"; "reason" => reason, "count" => count);
+    }
+
     Ok(())
 }
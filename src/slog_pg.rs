@@ -17,10 +17,7 @@ impl Decorator for ProgressBarDecorator {
         let mut rec = Vec::new();
         f(&mut ProgressBarRecordDecorator(&mut rec))?;
         crate::PROGRESS_BAR.println(String::from_utf8(rec).map_err(|e| {
-            std::io::Error::new(
-                std::io::ErrorKind::Other,
-                format!("Cannot convert log message to string: {e}"),
-            )
+            std::io::Error::other(format!("Cannot convert log message to string: {e}"))
         })?);
         Ok(())
     }
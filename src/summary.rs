@@ -1,12 +1,15 @@
 use std::borrow::Cow;
 use std::collections::{HashMap, VecDeque};
+use std::fmt;
 use std::fs::File;
 use std::io::{BufRead, BufReader};
 use std::path::Path;
 
-use serde::{Deserialize, Serialize};
+use serde::ser::SerializeStruct;
+use serde::{Deserialize, Serialize, Serializer};
 use slog::{warn, Logger};
 use thiserror::Error;
+use time::Duration;
 
 use crate::{TestResultData, TestResultType};
 
@@ -79,6 +82,174 @@ pub fn write_summary(
     Ok(())
 }
 
+/// Count not supported tests by their reason.
+///
+/// Variable parts of the reasons, like format names and numbers, are replaced by placeholders
+/// so that similar reasons are counted together. Sorted by descending count.
+pub fn not_supported_reasons(summary: &Summary) -> Vec<(String, usize)> {
+    count_reasons(summary, &TestResultType::NotSupported)
+}
+
+/// Count tests with the given result by their reason, like [`not_supported_reasons`].
+///
+/// Useful for `QualityWarning` and `CompatibilityWarning`.
+pub fn count_reasons(summary: &Summary, result: &TestResultType) -> Vec<(String, usize)> {
+    let mut reasons = HashMap::<String, usize>::new();
+    for (entry, data) in summary.0.values() {
+        if entry.result != *result {
+            continue;
+        }
+        let reason = data
+            .as_ref()
+            .and_then(|d| d.result.reason.as_deref())
+            .unwrap_or_default();
+        *reasons.entry(normalize_reason(reason)).or_default() += 1;
+    }
+    let mut reasons = reasons.into_iter().collect::<Vec<_>>();
+    reasons.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+    reasons
+}
+
+/// Duration and throughput of a run, computed by [`run_stats`].
+#[derive(Clone, Debug, PartialEq)]
+pub struct RunStats {
+    /// Number of tests that have a result.
+    pub tests: usize,
+    /// Time from the start of the first test to the end of the last test.
+    pub elapsed: Duration,
+}
+
+impl RunStats {
+    /// Tests per second, `0` if no time elapsed.
+    pub fn tests_per_second(&self) -> f64 {
+        let secs = self.elapsed.as_seconds_f64();
+        if secs > 0.0 {
+            self.tests as f64 / secs
+        } else {
+            0.0
+        }
+    }
+}
+
+/// Serialized with the elapsed time in seconds and the throughput.
+impl Serialize for RunStats {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut s = serializer.serialize_struct("RunStats", 3)?;
+        s.serialize_field("tests", &self.tests)?;
+        s.serialize_field("elapsed", &self.elapsed.as_seconds_f64())?;
+        s.serialize_field("tests_per_second", &self.tests_per_second())?;
+        s.end()
+    }
+}
+
+impl fmt::Display for RunStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} tests in {:.1}s ({:.1} tests/s)",
+            self.tests,
+            self.elapsed.as_seconds_f64(),
+            self.tests_per_second()
+        )
+    }
+}
+
+/// Compute the duration and throughput of a run from the test results in the summary.
+pub fn run_stats(summary: &Summary) -> RunStats {
+    let mut tests = 0;
+    let mut first_start = None;
+    let mut last_end = None;
+    for data in summary.0.values().filter_map(|(_, d)| d.as_ref()) {
+        tests += 1;
+        let end = data.start + data.duration;
+        if first_start.map(|s| data.start < s).unwrap_or(true) {
+            first_start = Some(data.start);
+        }
+        if last_end.map(|e| end > e).unwrap_or(true) {
+            last_end = Some(end);
+        }
+    }
+    let elapsed = match (first_start, last_end) {
+        (Some(start), Some(end)) => end - start,
+        _ => Duration::ZERO,
+    };
+    RunStats { tests, elapsed }
+}
+
+/// A test that took longer than in the baseline, returned by [`slower_tests`].
+#[derive(Clone, Debug, PartialEq)]
+pub struct SlowerTest<'a> {
+    pub name: &'a str,
+    pub baseline: Duration,
+    pub duration: Duration,
+}
+
+/// Durations of all tests that have a result, e.g. to use them as baseline for [`slower_tests`].
+pub fn test_durations(summary: &Summary) -> HashMap<String, Duration> {
+    summary
+        .0
+        .iter()
+        .filter_map(|(name, (_, data))| data.as_ref().map(|d| (name.to_string(), d.duration)))
+        .collect()
+}
+
+/// Find tests that took more than `ratio` times as long as in the baseline.
+///
+/// Tests that are not in the baseline are ignored. Sorted by descending slowdown.
+pub fn slower_tests<'a>(
+    summary: &Summary<'a>,
+    baseline: &HashMap<String, Duration>,
+    ratio: f64,
+) -> Vec<SlowerTest<'a>> {
+    let mut res = summary
+        .0
+        .iter()
+        .filter_map(|(name, (_, data))| {
+            let duration = data.as_ref()?.duration;
+            let baseline = *baseline.get(*name)?;
+            if duration.as_seconds_f64() > baseline.as_seconds_f64() * ratio {
+                Some(SlowerTest {
+                    name,
+                    baseline,
+                    duration,
+                })
+            } else {
+                None
+            }
+        })
+        .collect::<Vec<_>>();
+    let slowdown = |t: &SlowerTest| t.duration.as_seconds_f64() / t.baseline.as_seconds_f64();
+    res.sort_by(|a, b| {
+        slowdown(b)
+            .total_cmp(&slowdown(a))
+            .then_with(|| a.name.cmp(b.name))
+    });
+    res
+}
+
+/// Replace format names with `VK_FORMAT_*` and numbers with `N`.
+fn normalize_reason(reason: &str) -> String {
+    reason
+        .split(' ')
+        .map(|word| {
+            // Keep surrounding punctuation like parentheses and commas
+            let is_punctuation = |c: char| c.is_ascii_punctuation() && c != '_';
+            let core = word.trim_start_matches(is_punctuation);
+            let prefix = &word[..word.len() - core.len()];
+            let core = core.trim_end_matches(is_punctuation);
+            let suffix = &word[prefix.len() + core.len()..];
+            if core.starts_with("VK_FORMAT_") {
+                format!("{prefix}VK_FORMAT_*{suffix}")
+            } else if !core.is_empty() && core.chars().all(|c| c.is_ascii_digit()) {
+                format!("{prefix}N{suffix}")
+            } else {
+                word.to_string()
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
 pub fn create_xml_summary(
     logger: &Logger,
     tests: &[&str],
@@ -184,21 +355,14 @@ pub fn create_xml_summary(
 mod tests {
     use anyhow::Result;
     use junit_report::Report;
+    use time::OffsetDateTime;
 
     use super::*;
     use crate::*;
 
     async fn check_tests(args: &[&str]) -> Result<Report> {
         let logger = crate::tests::create_logger();
-        let run_options = RunOptions {
-            args: args.iter().map(|s| s.to_string()).collect(),
-            capture_dumps: true,
-            timeout: std::time::Duration::from_secs(2),
-            max_failures: 0,
-            fail_dir: None,
-            retry: true,
-            batch_size: BATCH_SIZE,
-        };
+        let run_options = crate::tests::create_options(args);
 
         // Read test file
         let test_file = tokio::fs::read_to_string("logs/in").await?;
@@ -304,4 +468,178 @@ mod tests {
 
         Ok(())
     }
+
+    #[test]
+    fn test_not_supported_reasons() {
+        let results = [
+            (
+                "a",
+                TestResultType::NotSupported,
+                Some("Format not supported: VK_FORMAT_R8_UNORM"),
+            ),
+            (
+                "b",
+                TestResultType::NotSupported,
+                Some("Format not supported: VK_FORMAT_D16_UNORM"),
+            ),
+            (
+                "c",
+                TestResultType::NotSupported,
+                Some("Requires 4 samples"),
+            ),
+            (
+                "d",
+                TestResultType::NotSupported,
+                Some("Requires 8 samples"),
+            ),
+            (
+                "e",
+                TestResultType::NotSupported,
+                Some("Format not supported: (VK_FORMAT_B8G8R8A8_SRGB)"),
+            ),
+            (
+                "f",
+                TestResultType::NotSupported,
+                Some("VK_KHR_maintenance4 not supported"),
+            ),
+            ("g", TestResultType::Pass, None),
+        ];
+
+        let mut summary = Summary::default();
+        for (name, result, reason) in &results {
+            summary.0.insert(
+                name,
+                (
+                    SummaryEntry {
+                        name: Cow::Borrowed(name),
+                        result: result.clone(),
+                        run_id: None,
+                    },
+                    Some(TestResultData {
+                        name,
+                        result: crate::TestResult {
+                            stdout: String::new(),
+                            variant: result.clone(),
+                            reason: reason.map(|r| r.to_string()),
+                            metrics: HashMap::new(),
+                        },
+                        start: OffsetDateTime::now_utc(),
+                        duration: Duration::ZERO,
+                        pid: None,
+                        fail_dir: None,
+                    }),
+                ),
+            );
+        }
+
+        assert_eq!(
+            not_supported_reasons(&summary),
+            [
+                ("Format not supported: VK_FORMAT_*".to_string(), 2),
+                ("Requires N samples".to_string(), 2),
+                ("Format not supported: (VK_FORMAT_*)".to_string(), 1),
+                ("VK_KHR_maintenance4 not supported".to_string(), 1),
+            ]
+        );
+    }
+
+    fn create_summary<'a>(durations: &[(&'a str, Duration)]) -> Summary<'a> {
+        let mut summary = Summary::default();
+        for (name, duration) in durations {
+            summary.0.insert(
+                name,
+                (
+                    SummaryEntry {
+                        name: Cow::Borrowed(name),
+                        result: TestResultType::Pass,
+                        run_id: None,
+                    },
+                    Some(TestResultData {
+                        name,
+                        result: crate::TestResult {
+                            stdout: String::new(),
+                            variant: TestResultType::Pass,
+                            reason: None,
+                            metrics: HashMap::new(),
+                        },
+                        start: OffsetDateTime::now_utc(),
+                        duration: *duration,
+                        pid: None,
+                        fail_dir: None,
+                    }),
+                ),
+            );
+        }
+        summary
+    }
+
+    #[test]
+    fn test_run_stats() {
+        let stats = run_stats(&Summary::default());
+        assert_eq!(stats.tests, 0);
+        assert_eq!(stats.tests_per_second(), 0.0);
+
+        let mut summary = create_summary(&[
+            ("a", Duration::seconds(2)),
+            ("b", Duration::seconds(3)),
+            ("c", Duration::seconds(1)),
+            ("d", Duration::seconds(1)),
+        ]);
+        let start = OffsetDateTime::now_utc();
+        for (name, offset) in [("a", 0), ("b", 1), ("c", 2), ("d", 7)] {
+            summary.0.get_mut(name).unwrap().1.as_mut().unwrap().start =
+                start + Duration::seconds(offset);
+        }
+        summary.0.get_mut("c").unwrap().1 = None;
+
+        let stats = run_stats(&summary);
+        assert_eq!(
+            stats,
+            RunStats {
+                tests: 3,
+                elapsed: Duration::seconds(8),
+            }
+        );
+        assert_eq!(
+            serde_json::to_string(&stats).unwrap(),
+            r#"{"tests":3,"elapsed":8.0,"tests_per_second":0.375}"#
+        );
+        assert_eq!(stats.tests_per_second(), 3.0 / 8.0);
+        assert_eq!(stats.to_string(), "3 tests in 8.0s (0.4 tests/s)");
+    }
+
+    #[test]
+    fn test_slower_tests() {
+        let baseline = create_summary(&[
+            ("a", Duration::seconds(1)),
+            ("b", Duration::seconds(2)),
+            ("c", Duration::seconds(3)),
+            ("d", Duration::seconds(4)),
+        ]);
+        let baseline = test_durations(&baseline);
+        assert_eq!(baseline.len(), 4);
+
+        let summary = create_summary(&[
+            ("a", Duration::seconds(3)),
+            ("b", Duration::seconds(3)),
+            ("c", Duration::seconds(9)),
+            ("d", Duration::seconds(1)),
+            ("e", Duration::seconds(100)),
+        ]);
+        assert_eq!(
+            slower_tests(&summary, &baseline, 2.0),
+            [
+                SlowerTest {
+                    name: "a",
+                    baseline: Duration::seconds(1),
+                    duration: Duration::seconds(3),
+                },
+                SlowerTest {
+                    name: "c",
+                    baseline: Duration::seconds(3),
+                    duration: Duration::seconds(9),
+                },
+            ]
+        );
+    }
 }
@@ -0,0 +1,5 @@
+#!/usr/bin/env bash
+# Wait $1 seconds, then run the remaining arguments as command
+sleep "$1"
+shift
+exec "$@"
@@ -0,0 +1,48 @@
+//! Send a real SIGINT to the process group of deqp-runner, like Ctrl+C in a terminal does.
+#![cfg(unix)]
+
+use std::os::unix::process::CommandExt;
+use std::process::{Command, Stdio};
+use std::time::Duration;
+
+#[test]
+fn test_ctrl_c_finishes_running_tests() {
+    let dir = tempfile::tempdir().unwrap();
+    let tests = dir.path().join("tests.txt");
+    std::fs::write(&tests, "1\n2\n3\n4\n").unwrap();
+
+    // Run in a new process group, like a job in a terminal
+    let mut runner = Command::new(env!("CARGO_BIN_EXE_deqp-runner"))
+        .arg("--no-progress")
+        .arg("--no-sort")
+        .arg("--jobs=1")
+        .arg("--output")
+        .arg(dir.path())
+        .arg("--tests")
+        .arg(&tests)
+        .args(["--", "test/test-delay.sh", "1", "test/test-sorted.sh"])
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .process_group(0)
+        .spawn()
+        .unwrap();
+
+    // Interrupt while the tests are running
+    std::thread::sleep(Duration::from_millis(500));
+    let status = Command::new("kill")
+        .args(["-s", "INT", "--", &format!("-{}", runner.id())])
+        .status()
+        .unwrap();
+    assert!(status.success());
+    runner.wait().unwrap();
+
+    let summary = std::fs::read_to_string(dir.path().join("summary.csv")).unwrap();
+    for t in ["1", "2", "3", "4"] {
+        assert!(
+            summary.lines().any(|l| l.starts_with(&format!("{t},Pass"))),
+            "Test {} did not pass:\n{}",
+            t,
+            summary
+        );
+    }
+}